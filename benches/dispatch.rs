@@ -0,0 +1,142 @@
+// Compares the job dispatch of the WorkerPool against the previous design, where every worker
+// locked a shared Mutex<mpsc::Receiver> to take the next job. Run with `cargo bench`.
+//
+// A single producer submits short jobs the way an acceptor thread does. Throughput is measured
+// from the first submit until the last job finished, latency is the time a job spent in the queue
+// before a worker started it.
+
+use std::{
+    hint::black_box,
+    sync::{atomic::{AtomicU64, Ordering}, mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chat_room::srv_http::workpool::WorkerPool;
+
+const JOBS: usize = 200_000;
+const QUEUE_CAPACITY: usize = 1024;
+const WORKER_COUNTS: [usize; 5] = [4, 8, 16, 32, 64];
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// The dispatch replaced by the lock-free channel, kept here as the baseline
+struct MutexPool {
+    sender: Option<mpsc::SyncSender<Job>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl MutexPool {
+    fn new(size: usize, queue_capacity: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_capacity);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..size)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || loop {
+                    let message = receiver.lock().unwrap().recv();
+                    match message {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+        MutexPool {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+}
+
+trait Pool {
+    fn execute(&self, job: Job);
+    fn finish(self);
+}
+
+impl Pool for MutexPool {
+    fn execute(&self, job: Job) {
+        self.sender.as_ref().unwrap().send(job).unwrap();
+    }
+
+    fn finish(mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+}
+
+impl Pool for WorkerPool {
+    fn execute(&self, job: Job) {
+        WorkerPool::execute(self, job);
+    }
+
+    fn finish(self) {
+        self.shutdown(None);
+    }
+}
+
+struct Report {
+    throughput: f64,
+    latencies: Vec<u64>,
+}
+
+impl Report {
+    fn percentile(&self, p: f64) -> Duration {
+        let index = ((self.latencies.len() - 1) as f64 * p).round() as usize;
+        Duration::from_nanos(self.latencies[index])
+    }
+}
+
+// a little work so the workers do not only fight over the queue
+fn work() {
+    let mut x = 0u64;
+    for i in 0..200 {
+        x = black_box(x.wrapping_mul(31).wrapping_add(i));
+    }
+    black_box(x);
+}
+
+fn run<P: Pool>(pool: P) -> Report {
+    let latencies: Arc<Vec<AtomicU64>> = Arc::new((0..JOBS).map(|_| AtomicU64::new(0)).collect());
+    let start = Instant::now();
+    for i in 0..JOBS {
+        let latencies = Arc::clone(&latencies);
+        let submitted = Instant::now();
+        pool.execute(Box::new(move || {
+            latencies[i].store(submitted.elapsed().as_nanos() as u64, Ordering::Relaxed);
+            work();
+        }));
+    }
+    pool.finish();
+    let elapsed = start.elapsed();
+
+    let mut latencies: Vec<u64> = latencies.iter().map(|l| l.load(Ordering::Relaxed)).collect();
+    latencies.sort_unstable();
+    Report {
+        throughput: JOBS as f64 / elapsed.as_secs_f64(),
+        latencies,
+    }
+}
+
+fn print_report(name: &str, workers: usize, report: &Report) {
+    println!(
+        "{:<10} {:>7} {:>12.0} {:>10.1?} {:>10.1?} {:>10.1?}",
+        name,
+        workers,
+        report.throughput,
+        report.percentile(0.5),
+        report.percentile(0.99),
+        report.percentile(0.999)
+    );
+}
+
+fn main() {
+    println!("{:<10} {:>7} {:>12} {:>10} {:>10} {:>10}", "dispatch", "workers", "jobs/s", "p50", "p99", "p99.9");
+    for workers in WORKER_COUNTS {
+        print_report("mutex", workers, &run(MutexPool::new(workers, QUEUE_CAPACITY)));
+        print_report("lock-free", workers, &run(WorkerPool::new(workers, QUEUE_CAPACITY)));
+    }
+}
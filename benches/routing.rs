@@ -0,0 +1,117 @@
+// Compares the prefix tree Router against the previous design, where every request was matched
+// against the regex of every route in registration order. Run with `cargo bench --bench routing`.
+//
+// The routes are shaped like a REST API, every resource has a collection, an item and a nested
+// collection route. The paths are spread over all resources, so the linear scan has to go through
+// half of the routes on average.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use chat_room::srv_http::router::{RouteAddress, Router};
+use regex::Regex;
+
+const LOOKUPS: usize = 200_000;
+const ROUTE_COUNTS: [usize; 4] = [10, 100, 300, 1000];
+
+// The matching replaced by the router, kept here as the baseline
+struct RegexRoutes {
+    routes: Vec<(Regex, Vec<String>)>,
+}
+
+impl RegexRoutes {
+    fn new(templates: &[String]) -> Self {
+        let routes = templates
+            .iter()
+            .map(|template| {
+                let mut params = Vec::new();
+                let mut pattern = String::new();
+                let mut param: Option<String> = None;
+                for c in template.chars() {
+                    match (c, &mut param) {
+                        ('{', _) => param = Some(String::new()),
+                        ('}', _) => {
+                            params.extend(param.take());
+                            pattern += "([^/]+)";
+                        }
+                        (c, Some(p)) => p.push(c),
+                        (c, None) => pattern.push(c),
+                    }
+                }
+                pattern.push('$');
+                (Regex::new(&pattern).unwrap(), params)
+            })
+            .collect();
+        RegexRoutes { routes }
+    }
+
+    fn lookup(&self, path: &str) -> Option<usize> {
+        let index = self.routes.iter().position(|r| r.0.is_match(path))?;
+        let captures = self.routes[index].0.captures(path)?;
+        let params: Vec<(&String, &str)> = self.routes[index].1
+            .iter()
+            .zip(captures.iter().skip(1).flatten().map(|m| m.as_str()))
+            .collect();
+        black_box(params);
+        Some(index)
+    }
+}
+
+fn templates(count: usize) -> Vec<String> {
+    (0..count)
+        .map(|i| match i % 3 {
+            0 => format!("/api/resource{}", i / 3),
+            1 => format!("/api/resource{}/{{id}}", i / 3),
+            _ => format!("/api/resource{}/{{id}}/items/{{item}}", i / 3),
+        })
+        .collect()
+}
+
+fn paths(count: usize) -> Vec<String> {
+    (0..count)
+        .map(|i| match i % 3 {
+            0 => format!("/api/resource{}", i / 3),
+            1 => format!("/api/resource{}/{}", i / 3, i),
+            _ => format!("/api/resource{}/{}/items/{}", i / 3, i, i * 7),
+        })
+        .collect()
+}
+
+fn measure<F: Fn(&str) -> bool>(paths: &[String], lookup: F) -> Duration {
+    let start = Instant::now();
+    for i in 0..LOOKUPS {
+        assert!(lookup(black_box(&paths[i % paths.len()])));
+    }
+    start.elapsed() / LOOKUPS as u32
+}
+
+fn print_report(name: &str, routes: usize, per_lookup: Duration) {
+    println!(
+        "{:<8} {:>7} {:>12.0} {:>10.1?}",
+        name,
+        routes,
+        1.0 / per_lookup.as_secs_f64(),
+        per_lookup
+    );
+}
+
+fn main() {
+    println!("{:<8} {:>7} {:>12} {:>10}", "router", "routes", "lookups/s", "lookup");
+    for count in ROUTE_COUNTS {
+        let templates = templates(count);
+        let paths = paths(count);
+
+        let regex_routes = RegexRoutes::new(&templates);
+        let per_lookup = measure(&paths, |path| regex_routes.lookup(path).is_some());
+        print_report("regex", count, per_lookup);
+
+        let addresses = templates.into_iter().map(|t| RouteAddress::new(t).unwrap()).collect();
+        let router = Router::new(addresses, |a| a);
+        let per_lookup = measure(&paths, |path| {
+            router.lookup(path).and_then(|found| found.select(|_| true)).map(black_box).is_some()
+        });
+        print_report("tree", count, per_lookup);
+    }
+}
@@ -0,0 +1,30 @@
+// An async server, run with `cargo run --example async_server --features async`
+use std::time::Duration;
+
+use http::StatusCode;
+
+use chat_room::srv_http::async_server::{AsyncHttpServerBuilder, AsyncRoute};
+use chat_room::srv_http::http_constants::HttpMethod;
+use chat_room::srv_http::request::{HttpRequest, ParamError};
+use chat_room::srv_http::response::HttpResponse;
+
+// stands in for a query on an async database client
+async fn delayed(request: HttpRequest) -> Result<HttpResponse, ParamError> {
+    let millis: u64 = request.param("millis")?;
+    tokio::time::sleep(Duration::from_millis(millis)).await;
+    Ok(HttpResponse::new(StatusCode::OK).text(format!("Waited {} ms", millis)))
+}
+
+#[tokio::main]
+async fn main() {
+    let server = AsyncHttpServerBuilder::new()
+        .bind("127.0.0.1:3003")
+        .add_route(AsyncRoute::new("/", HttpMethod::GET, |_| async { StatusCode::OK }))
+        .add_route(AsyncRoute::new("/delay/{millis}", HttpMethod::GET, delayed))
+        .build()
+        .expect("Unable to build the server");
+
+    server.run_until(async {
+        let _ = tokio::signal::ctrl_c().await;
+    }).await;
+}
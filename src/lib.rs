@@ -0,0 +1,4 @@
+pub mod srv_http;
+pub mod util;
+#[macro_use(concat_string)]
+extern crate concat_string;
@@ -1,20 +1,32 @@
-use services::example_services::{HomeHandler, SleepHandler, AnimalResponder};
-use srv_http::http_constants::HttpMethod;
-use srv_http::server_builder::HttpServerBuilder;
-use srv_http::service::Route;
-pub mod srv_http;
+use std::time::Duration;
+use http::StatusCode;
+use services::example_services::{SleepHandler, animal_responder, animal_by_id, ReportHandler, EchoHandler, VisitCounter, count_visit};
+use services::example_middleware::LoggingMiddleware;
+use chat_room::srv_http::extract::typed;
+use chat_room::srv_http::http_constants::HttpMethod;
+use chat_room::srv_http::server_builder::{HttpServerBuilder, OverloadPolicy};
+use chat_room::srv_http::service::Route;
 pub mod services;
-pub mod util;
-#[macro_use(concat_string)]
-extern crate concat_string;
 
 fn main() {
     let server = HttpServerBuilder::new()
         .bind("127.0.0.1:3001")
+        .bind("127.0.0.1:3002")
         .worker_pool_limit(32)
-        .add_route(Route::new("/", HttpMethod::GET, HomeHandler))
+        .min_workers(4)
+        .queue_capacity(64)
+        .overload_policy(OverloadPolicy::Reject { retry_after: Duration::from_secs(5) })
+        .add_middleware(LoggingMiddleware)
+        .with_state(VisitCounter::default())
+        .add_route(Route::new("/", HttpMethod::GET, |_| StatusCode::OK))
+        .add_route(Route::new("/report", HttpMethod::GET, ReportHandler))
+        .add_route(Route::new("/visits", HttpMethod::GET, typed(count_visit)))
+        .add_route(Route::new("/echo", HttpMethod::POST, EchoHandler))
         .add_route(Route::new("/sleep", HttpMethod::GET, SleepHandler))
-        .add_route(Route::new("/animal", HttpMethod::POST, AnimalResponder))
-        .build();
+        .add_route(Route::new("/animal", HttpMethod::POST, typed(animal_responder)))
+        .add_route(Route::new("/animal/{id:u64}", HttpMethod::GET, animal_by_id))
+        .build()
+        .expect("Unable to build the server");
+    server.handle().shutdown_on_signals();
     server.run();
 }
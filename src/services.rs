@@ -1 +1,2 @@
 pub mod example_services;
+pub mod example_middleware;
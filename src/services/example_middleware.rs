@@ -0,0 +1,13 @@
+use std::time::Instant;
+use chat_room::srv_http::{middleware::{Middleware, Next}, request::HttpRequest, response::HttpResponse};
+
+pub struct LoggingMiddleware;
+impl Middleware for LoggingMiddleware {
+    fn handle(&self, request: HttpRequest, next: Next) -> HttpResponse {
+        let start = Instant::now();
+        let line = format!("{} {}", request.method, request.uri);
+        let response = next.run(request);
+        println!("{} -> {} in {:?}", line, response.status_code, start.elapsed());
+        response
+    }
+}
@@ -1,12 +1,35 @@
 use std::{thread, time};
+use std::sync::atomic::{AtomicU64, Ordering};
 use http::StatusCode;
 use serde::{Deserialize, Serialize};
-use crate::srv_http::{service::{RouteHandler, ServerError}, response::HttpResponse, request::HttpRequest};
+use chat_room::srv_http::{extract::{Json, State}, service::{RouteHandler, ServerError}, response::{Body, HttpResponse}, request::{HttpRequest, ParamError}};
 
-pub struct HomeHandler;
-impl RouteHandler for HomeHandler {
+pub struct ReportHandler;
+impl RouteHandler for ReportHandler {
     fn respond(&self, _: HttpRequest) -> HttpResponse {
-        HttpResponse::new(StatusCode::OK)
+        // the rows are generated while the response is written instead of buffered up front
+        let rows = (1..=10_000).map(|i| format!("{},{}\n", i, i * i).into_bytes());
+        let mut response = HttpResponse::new(StatusCode::OK).body(Body::from_chunks(rows));
+        response.add_header(String::from("Content-Type"), String::from("text/csv"));
+        response
+    }
+}
+
+#[derive(Default)]
+pub struct VisitCounter {
+    visits: AtomicU64,
+}
+
+pub fn count_visit(State(counter): State<VisitCounter>) -> HttpResponse {
+    let visits = counter.visits.fetch_add(1, Ordering::Relaxed) + 1;
+    HttpResponse::new(StatusCode::OK).text(visits.to_string())
+}
+
+pub struct EchoHandler;
+impl RouteHandler for EchoHandler {
+    fn respond(&self, request: HttpRequest) -> HttpResponse {
+        let content_type = request.header("Content-Type").unwrap_or("application/octet-stream").to_string();
+        HttpResponse::new(StatusCode::OK).bytes(&content_type, request.body)
     }
 }
 
@@ -28,25 +51,28 @@ pub struct AnimalResponse {
     name: String,
 }
 
-pub struct AnimalResponder;
+fn find_animal(id: u64) -> Result<AnimalResponse, ServerError> {
+    match id {
+        1 => Ok(AnimalResponse { name: String::from("Dog") }),
+        2 => Ok(AnimalResponse { name: String::from("Cat") }),
+        _ => Err(ServerError::Fail),
+    }
+}
 
-impl RouteHandler for AnimalResponder {
-    fn respond(&self, request: HttpRequest) -> HttpResponse {
-        let request = serde_json::from_str(&request.body);
-        let request: AnimalRequest = match request {
-            Ok(r) => r,
-            Err(_) => return HttpResponse::new(StatusCode::BAD_REQUEST),
-        };
-
-        let response = match request.id {
-            1 => Ok(AnimalResponse { name: String::from("Dog") }),
-            2 => Ok(AnimalResponse { name: String::from("Cat") }),
-            _ => Err(ServerError::Fail),
-        };
-
-        match response {
-            Ok(r) => HttpResponse::new(StatusCode::OK).json(&r),
-            Err(_) => HttpResponse::new(StatusCode::NOT_FOUND),
-        }
+// registered with typed(animal_responder), the JSON body is deserialized by the Json extractor
+pub fn animal_responder(Json(request): Json<AnimalRequest>) -> HttpResponse {
+    match find_animal(request.id) {
+        Ok(r) => HttpResponse::new(StatusCode::OK).json(&r),
+        Err(_) => HttpResponse::new(StatusCode::NOT_FOUND),
+    }
+}
+
+// a failing param is turned into a 400 response by the ? operator
+pub fn animal_by_id(request: HttpRequest) -> Result<HttpResponse, ParamError> {
+    let id: u64 = request.param("id")?;
+
+    match find_animal(id) {
+        Ok(r) => Ok(HttpResponse::new(StatusCode::OK).json(&r)),
+        Err(_) => Ok(HttpResponse::new(StatusCode::NOT_FOUND)),
     }
 }
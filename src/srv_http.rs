@@ -1,7 +1,16 @@
 pub mod server_builder;
+pub mod server_handle;
 pub mod service;
+pub mod middleware;
+pub mod extract;
+pub mod state;
 pub mod http_constants;
 pub mod request;
 pub mod response;
-mod workpool;
+pub mod workpool;
+pub mod router;
+#[cfg(feature = "async")]
+pub mod async_server;
 mod server;
+mod reactor;
+mod timeout;
@@ -0,0 +1,553 @@
+use std::future::Future;
+use std::io::{self, Write};
+use std::net;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use http::StatusCode;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+
+use super::http_constants::{HttpMethod, HttpVersion};
+use super::request::{HttpRequest, ParseError, RequestDecoder, RequestLimits};
+use super::response::{Body, HttpResponse, IntoResponse};
+use super::router::{RouteAddress, Router};
+use super::server::{find_route, set_connection};
+use super::server_builder::{
+    BODY_READ_TIMEOUT_DEFAULT, HEADER_READ_TIMEOUT_DEFAULT, KEEP_ALIVE_TIMEOUT_DEFAULT, REQUEST_HEAD_TIMEOUT_DEFAULT,
+    SHUTDOWN_TIMEOUT_DEFAULT, WRITE_TIMEOUT_DEFAULT,
+};
+use super::service::{route_conflicts, DefaultErrorRenderer, ErrorRenderer, ServerError};
+use super::state::AppState;
+
+const ACCEPT_QUEUE_SIZE: usize = 1024;
+const STREAM_QUEUE_SIZE: usize = 16;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+// The async counterpart of RouteHandler. Handlers run as tokio tasks, so they can await async
+// database clients and other I/O without holding a thread.
+pub trait AsyncRouteHandler: Send + Sync {
+    fn respond(&self, request: HttpRequest) -> BoxFuture<'_, HttpResponse>;
+}
+
+// async fns and closures returning an async block can be registered directly, like a
+// RouteHandler they may answer with anything that implements IntoResponse
+impl<F, Fut, R> AsyncRouteHandler for F
+where F: Fn(HttpRequest) -> Fut + Send + Sync, Fut: Future<Output = R> + Send + 'static, R: IntoResponse {
+    fn respond(&self, request: HttpRequest) -> BoxFuture<'_, HttpResponse> {
+        let response = self(request);
+        Box::pin(async move { response.await.into_response() })
+    }
+}
+
+pub struct AsyncRoute {
+    pub uri: String,
+    pub method: HttpMethod,
+    pub handler: Arc<dyn AsyncRouteHandler>,
+}
+
+impl AsyncRoute {
+    pub fn new<T>(uri: &str, method: HttpMethod, handler: T) -> Self
+    where T: AsyncRouteHandler + 'static {
+        AsyncRoute {
+            uri: String::from(uri),
+            method,
+            handler: Arc::new(handler),
+        }
+    }
+}
+
+struct AsyncRegexRoute {
+    uri: RouteAddress,
+    method: HttpMethod,
+    handler: Arc<dyn AsyncRouteHandler>,
+}
+
+// what every connection task needs to answer its requests
+struct Shared {
+    routes: Router<AsyncRegexRoute>,
+    state: Arc<AppState>,
+    keep_alive_timeout: Duration,
+    header_read_timeout: Duration,
+    request_head_timeout: Duration,
+    body_read_timeout: Duration,
+    write_timeout: Duration,
+    request_limits: RequestLimits,
+    error_renderer: Arc<dyn ErrorRenderer>,
+}
+
+pub struct AsyncHttpServerBuilder {
+    bindings: Vec<net::SocketAddr>,
+    routes: Vec<AsyncRoute>,
+    keep_alive_timeout: Duration,
+    header_read_timeout: Duration,
+    request_head_timeout: Duration,
+    body_read_timeout: Duration,
+    write_timeout: Duration,
+    request_limits: RequestLimits,
+    error_renderer: Arc<dyn ErrorRenderer>,
+    state: AppState,
+    shutdown_timeout: Duration,
+}
+
+impl Default for AsyncHttpServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AsyncHttpServerBuilder {
+    pub fn new() -> Self {
+        AsyncHttpServerBuilder {
+            bindings: Vec::new(),
+            routes: Vec::new(),
+            keep_alive_timeout: KEEP_ALIVE_TIMEOUT_DEFAULT,
+            header_read_timeout: HEADER_READ_TIMEOUT_DEFAULT,
+            request_head_timeout: REQUEST_HEAD_TIMEOUT_DEFAULT,
+            body_read_timeout: BODY_READ_TIMEOUT_DEFAULT,
+            write_timeout: WRITE_TIMEOUT_DEFAULT,
+            request_limits: RequestLimits::default(),
+            error_renderer: Arc::new(DefaultErrorRenderer::new()),
+            state: AppState::default(),
+            shutdown_timeout: SHUTDOWN_TIMEOUT_DEFAULT,
+        }
+    }
+
+    pub fn keep_alive_timeout(mut self, timeout: Duration) -> Self {
+        assert!(!timeout.is_zero());
+        self.keep_alive_timeout = timeout;
+        self
+    }
+
+    // the timeouts and limits below work as on the HttpServerBuilder
+    pub fn header_read_timeout(mut self, timeout: Duration) -> Self {
+        assert!(!timeout.is_zero());
+        self.header_read_timeout = timeout;
+        self
+    }
+
+    pub fn request_head_timeout(mut self, timeout: Duration) -> Self {
+        assert!(!timeout.is_zero());
+        self.request_head_timeout = timeout;
+        self
+    }
+
+    pub fn body_read_timeout(mut self, timeout: Duration) -> Self {
+        assert!(!timeout.is_zero());
+        self.body_read_timeout = timeout;
+        self
+    }
+
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        assert!(!timeout.is_zero());
+        self.write_timeout = timeout;
+        self
+    }
+
+    pub fn max_request_line(mut self, bytes: usize) -> Self {
+        self.request_limits.max_request_line = bytes;
+        self
+    }
+
+    pub fn max_headers(mut self, count: usize) -> Self {
+        self.request_limits.max_headers = count;
+        self
+    }
+
+    pub fn max_header_bytes(mut self, bytes: usize) -> Self {
+        self.request_limits.max_header_bytes = bytes;
+        self
+    }
+
+    pub fn max_body_size(mut self, bytes: usize) -> Self {
+        self.request_limits.max_body_size = bytes;
+        self
+    }
+
+    // renders the responses to requests that could not be read, see HttpServerBuilder
+    pub fn error_renderer<R>(mut self, renderer: R) -> Self
+    where R: ErrorRenderer + 'static {
+        self.error_renderer = Arc::new(renderer);
+        self
+    }
+
+    // how long run_until waits for in-flight requests once its shutdown future completes
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    pub fn with_state<S: Send + Sync + 'static>(mut self, state: S) -> Self {
+        self.state.insert(state);
+        self
+    }
+
+    pub fn bind<A: net::ToSocketAddrs>(mut self, address: A) -> Self {
+        let mut binding = address
+            .to_socket_addrs()
+            .expect("Unable to resolve domain");
+        if let Some(b) = binding.next() {
+            self.bindings.push(b);
+        }
+        self
+    }
+
+    pub fn add_route(mut self, route: AsyncRoute) -> Self {
+        self.routes.push(route);
+        self
+    }
+
+    // fails like HttpServerBuilder::build on conflicting routes, templates that cannot be matched
+    // and addresses that cannot be bound
+    pub fn build(self) -> Result<AsyncHttpServer, ServerError> {
+        let conflicts = route_conflicts(self.routes.iter().map(|r| (&r.method, r.uri.as_str())));
+        if !conflicts.is_empty() {
+            return Err(ServerError::RouteConflict(conflicts));
+        }
+
+        let routes = self.routes
+            .into_iter()
+            .map(|route| Ok(AsyncRegexRoute {
+                uri: RouteAddress::new(route.uri)?,
+                method: route.method,
+                handler: route.handler,
+            }))
+            .collect::<Result<Vec<AsyncRegexRoute>, ServerError>>()?;
+
+        let mut listeners: Vec<net::TcpListener> = Vec::new();
+        for binding in &self.bindings {
+            listeners.push(net::TcpListener::bind(binding).map_err(ServerError::Bind)?);
+        }
+
+        Ok(AsyncHttpServer {
+            listeners,
+            shared: Arc::new(Shared {
+                routes: Router::new(routes, |r| &r.uri),
+                state: Arc::new(self.state),
+                keep_alive_timeout: self.keep_alive_timeout,
+                header_read_timeout: self.header_read_timeout,
+                request_head_timeout: self.request_head_timeout,
+                body_read_timeout: self.body_read_timeout,
+                write_timeout: self.write_timeout,
+                request_limits: self.request_limits,
+                error_renderer: self.error_renderer,
+            }),
+            shutdown_timeout: self.shutdown_timeout,
+        })
+    }
+}
+
+pub struct AsyncHttpServer {
+    pub listeners: Vec<net::TcpListener>,
+    shared: Arc<Shared>,
+    shutdown_timeout: Duration,
+}
+
+impl AsyncHttpServer {
+    pub async fn run(self) {
+        self.run_until(std::future::pending()).await
+    }
+
+    // Serves connections until the shutdown future completes, e.g. tokio::signal::ctrl_c().
+    // Idle connections are then closed and in-flight requests get shutdown_timeout to finish.
+    pub async fn run_until<F: Future<Output = ()>>(self, shutdown: F) {
+        let (accepted_sender, mut accepted) = mpsc::channel::<TcpStream>(ACCEPT_QUEUE_SIZE);
+        let mut acceptors = JoinSet::new();
+        for listener in self.listeners {
+            let listener = match listener.set_nonblocking(true).and_then(|_| TcpListener::from_std(listener)) {
+                Ok(l) => l,
+                Err(err) => {
+                    println!("Unable to register listener: {}", err);
+                    continue
+                }
+            };
+            if let Ok(address) = listener.local_addr() {
+                println!("Now listening on {}", address);
+            }
+            let accepted_sender = accepted_sender.clone();
+            acceptors.spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, _)) => {
+                            if accepted_sender.send(stream).await.is_err() {
+                                break
+                            }
+                        }
+                        Err(err) => println!("Stream IO Failure: {}", err),
+                    }
+                }
+            });
+        }
+        drop(accepted_sender);
+
+        let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+        let mut connections = JoinSet::new();
+        tokio::pin!(shutdown);
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => break,
+                Some(stream) = accepted.recv() => {
+                    connections.spawn(handle_connection(stream, self.shared.clone(), shutdown_receiver.clone()));
+                }
+                // finished connections are reaped so the set does not grow with every connection
+                Some(_) = connections.join_next(), if !connections.is_empty() => (),
+                else => break,
+            }
+        }
+        acceptors.abort_all();
+
+        println!("Stopped accepting connections; waiting for in-flight requests.");
+        let _ = shutdown_sender.send(true);
+        let drained = tokio::time::timeout(self.shutdown_timeout, async {
+            while connections.join_next().await.is_some() {}
+        });
+        if drained.await.is_err() {
+            println!("Shutdown timed out; abandoning in-flight requests.");
+            connections.abort_all();
+        }
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, shared: Arc<Shared>, mut shutdown: watch::Receiver<bool>) {
+    // bytes read past the end of a request are kept for the next one
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut decoder = RequestDecoder::new(shared.request_limits);
+
+    loop {
+        let mut request_started = Instant::now();
+        let request = loop {
+            match decoder.decode(&mut buffer) {
+                Ok(Some(request)) => break request,
+                Ok(None) => (),
+                Err(err) => return reject(&mut stream, &shared, err).await,
+            }
+
+            // the request is held to the same timeouts as in the threaded mode
+            let idle = buffer.is_empty() && decoder.is_reading_head();
+            let timeout = if idle {
+                shared.keep_alive_timeout
+            } else if decoder.is_reading_head() {
+                let remaining = shared.request_head_timeout.saturating_sub(request_started.elapsed());
+                shared.header_read_timeout.min(remaining)
+            } else {
+                shared.body_read_timeout
+            };
+
+            // an idle connection is closed on shutdown, one in the middle of a request is not
+            let read = tokio::select! {
+                read = tokio::time::timeout(timeout, stream.read_buf(&mut buffer)) => read,
+                _ = shutdown.changed(), if idle => return,
+            };
+            match read {
+                Ok(Ok(n)) if n > 0 => {
+                    if idle {
+                        request_started = Instant::now();
+                    }
+                }
+                Err(_) if !idle => return reject(&mut stream, &shared, ParseError::Incomplete).await,
+                _ => return,
+            }
+        };
+
+        let response = respond(&shared, request, *shutdown.borrow());
+        let (response, version, keep_alive) = response.await;
+        if write_response(&mut stream, response, version, shared.write_timeout).await.is_err() || !keep_alive {
+            return
+        }
+    }
+}
+
+// Answers a request that could not be read, if it has an answer, before the connection is closed
+async fn reject(stream: &mut TcpStream, shared: &Shared, err: ParseError) {
+    println!("{}", err);
+    if let Some(status_code) = err.status_code() {
+        let mut response = shared.error_renderer.render(&err, status_code);
+        response.add_header(String::from("Connection"), String::from("close"));
+        let _ = write_response(stream, response, HttpVersion::Http11, shared.write_timeout).await;
+    }
+}
+
+// Routes the request and prepares the response. Returns the version to answer with and whether the
+// connection stays open afterwards.
+async fn respond(shared: &Shared, mut request: HttpRequest, shutting_down: bool) -> (HttpResponse, HttpVersion, bool) {
+    request.state = shared.state.clone();
+    let keep_alive = request.keep_alive();
+    let version = request.version;
+
+    let mut response = match find_route(&shared.routes, &mut request, |r| &r.method) {
+        Ok(route) => {
+            let handler = route.handler.clone();
+            // the handler runs as its own task, so a panic is answered with a 500 instead of taking
+            // down the connection
+            match tokio::spawn(async move { handler.respond(request).await }).await {
+                Ok(response) => response,
+                Err(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+            }
+        }
+        Err(response) => response,
+    };
+    let keep_alive = set_connection(&mut response, version, keep_alive, shutting_down);
+    (response, version, keep_alive)
+}
+
+// Streamed bodies come from blocking readers and iterators. They are written on a blocking thread
+// and passed to the connection chunk by chunk, so a slow producer does not stall the runtime. A
+// client that stops reading fails the write, which also ends the blocking thread at its next chunk.
+async fn write_response(stream: &mut TcpStream, response: HttpResponse, version: HttpVersion, timeout: Duration) -> io::Result<()> {
+    if let Body::Bytes(_) = response.body {
+        let mut out: Vec<u8> = Vec::new();
+        response.write_to(&mut out, &version)?;
+        return write_timed(stream, &out, timeout).await;
+    }
+
+    let (sender, mut receiver) = mpsc::channel(STREAM_QUEUE_SIZE);
+    let writer = tokio::task::spawn_blocking(move || response.write_to(&mut ChannelWriter(sender), &version));
+    while let Some(chunk) = receiver.recv().await {
+        write_timed(stream, &chunk, timeout).await?;
+    }
+    writer.await.map_err(io::Error::other)?
+}
+
+// Writes all of buf, a single write may stall for at most the timeout like in the threaded mode
+async fn write_timed(stream: &mut TcpStream, mut buf: &[u8], timeout: Duration) -> io::Result<()> {
+    while !buf.is_empty() {
+        let written = tokio::time::timeout(timeout, stream.write(buf))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        if written == 0 {
+            return Err(io::Error::from(io::ErrorKind::WriteZero));
+        }
+        buf = &buf[written..];
+    }
+    Ok(())
+}
+
+struct ChannelWriter(mpsc::Sender<Vec<u8>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(buf.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[allow(dead_code)]
+async fn greet(request: HttpRequest) -> Result<HttpResponse, super::request::ParamError> {
+    let name: String = request.param("name")?;
+    tokio::task::yield_now().await;
+    Ok(HttpResponse::new(StatusCode::OK).text(concat_string!("Hello ", name)))
+}
+
+#[test]
+fn serves_async_handlers() {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    let server = AsyncHttpServerBuilder::new()
+        .bind("127.0.0.1:0")
+        .add_route(AsyncRoute::new("/greet/{name}", HttpMethod::GET, greet))
+        .add_route(AsyncRoute::new("/chunks", HttpMethod::GET, |_| async {
+            HttpResponse::new(StatusCode::OK).body(Body::from_chunks(vec![b"a".to_vec(), b"b".to_vec()]))
+        }))
+        .build()
+        .unwrap();
+    let address = server.listeners[0].local_addr().unwrap();
+
+    let response = runtime.block_on(async move {
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(server.run_until(async { let _ = stopped.await; }));
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client
+            .write_all(b"GET /greet/bob HTTP/1.1\r\n\r\nGET /chunks HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        stop.send(()).unwrap();
+        server.await.unwrap();
+        response
+    });
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: 9\r\n\r\nHello bob"));
+    assert!(response.ends_with("Connection: close\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\n1\r\nb\r\n0\r\n\r\n"));
+}
+
+#[test]
+fn holds_requests_to_limits_and_timeouts() {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    let server = AsyncHttpServerBuilder::new()
+        .bind("127.0.0.1:0")
+        .header_read_timeout(Duration::from_millis(100))
+        .max_body_size(4)
+        .add_route(AsyncRoute::new("/", HttpMethod::POST, |_| async { StatusCode::OK }))
+        .build()
+        .unwrap();
+    let address = server.listeners[0].local_addr().unwrap();
+
+    let (too_large, stalled) = runtime.block_on(async move {
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(server.run_until(async { let _ = stopped.await; }));
+        let exchange = |request: &'static [u8]| async move {
+            let mut client = TcpStream::connect(address).await.unwrap();
+            client.write_all(request).await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let too_large = exchange(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello").await;
+        let stalled = exchange(b"POST / HTTP/1.1\r\nContent-").await;
+        stop.send(()).unwrap();
+        server.await.unwrap();
+        (too_large, stalled)
+    });
+
+    assert!(too_large.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+    assert!(stalled.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+}
+
+#[test]
+fn closes_connection_that_stops_reading() {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    let server = AsyncHttpServerBuilder::new()
+        .bind("127.0.0.1:0")
+        .write_timeout(Duration::from_millis(100))
+        .add_route(AsyncRoute::new("/endless", HttpMethod::GET, |_| async {
+            HttpResponse::new(StatusCode::OK).body(Body::from_chunks(std::iter::repeat(vec![0; 64 * 1024])))
+        }))
+        .build()
+        .unwrap();
+    let address = server.listeners[0].local_addr().unwrap();
+
+    let finished = runtime.block_on(async move {
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(server.run_until(async { let _ = stopped.await; }));
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(b"GET /endless HTTP/1.1\r\n\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        // the server gave up on the stalled write and closed, so the endless body ends
+        let mut response = Vec::new();
+        let finished = tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut response)).await;
+        stop.send(()).unwrap();
+        server.await.unwrap();
+        finished.is_ok()
+    });
+
+    assert!(finished);
+}
+
+#[test]
+fn rejects_conflicting_async_routes() {
+    let result = AsyncHttpServerBuilder::new()
+        .add_route(AsyncRoute::new("/user/{id}", HttpMethod::GET, |_| async { StatusCode::OK }))
+        .add_route(AsyncRoute::new("/user/{name}", HttpMethod::GET, |_| async { StatusCode::OK }))
+        .build();
+    assert!(matches!(result, Err(ServerError::RouteConflict(conflicts)) if conflicts.len() == 1));
+}
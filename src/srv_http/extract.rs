@@ -0,0 +1,280 @@
+use core::fmt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use http::StatusCode;
+use serde::de::DeserializeOwned;
+use serde_json::error::Category;
+
+use super::{
+    request::HttpRequest,
+    response::{HttpResponse, IntoResponse},
+    service::RouteHandler,
+};
+
+// Types that can be built from an incoming request. A failed extraction is turned into a
+// Rejection, which converts into the response that is sent back to the client.
+pub trait FromRequest: Sized {
+    fn from_request(request: &HttpRequest) -> Result<Self, Rejection>;
+}
+
+#[derive(Debug)]
+pub struct Rejection {
+    pub status_code: StatusCode,
+    pub message: String,
+}
+
+impl Rejection {
+    pub fn new<S: Into<String>>(status_code: StatusCode, message: S) -> Self {
+        Rejection {
+            status_code,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.status_code, self.message)
+    }
+}
+
+impl From<Rejection> for HttpResponse {
+    fn from(rejection: Rejection) -> Self {
+        HttpResponse::new(rejection.status_code).text(rejection.message)
+    }
+}
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> HttpResponse {
+        self.into()
+    }
+}
+
+impl HttpRequest {
+    pub fn extract<E: FromRequest>(&self) -> Result<E, Rejection> {
+        E::from_request(self)
+    }
+}
+
+// Deserializes a JSON body, the request must be sent with a JSON Content-Type
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Json<T> {
+    fn from_request(request: &HttpRequest) -> Result<Self, Rejection> {
+        let is_json = |m: &str| m == "application/json" || m.ends_with("+json");
+        if !media_type(request).is_some_and(|m| is_json(&m)) {
+            return Err(Rejection::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, "Expected a JSON Content-Type"));
+        }
+        match serde_json::from_slice(&request.body) {
+            Ok(value) => Ok(Json(value)),
+            // the body is valid JSON but does not have the expected shape
+            Err(err) if err.classify() == Category::Data => {
+                Err(Rejection::new(StatusCode::UNPROCESSABLE_ENTITY, err.to_string()))
+            }
+            Err(err) => Err(Rejection::new(StatusCode::BAD_REQUEST, err.to_string())),
+        }
+    }
+}
+
+// Deserializes the query parameters
+pub struct Query<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Query<T> {
+    fn from_request(request: &HttpRequest) -> Result<Self, Rejection> {
+        // query parameters are stored as sent, joining them gives back the query string
+        let query: Vec<String> = request.query_params
+            .iter()
+            .map(|qp| concat_string!(qp.0, "=", qp.1))
+            .collect();
+        serde_urlencoded::from_str(&query.join("&"))
+            .map(Query)
+            .map_err(|err| Rejection::new(StatusCode::BAD_REQUEST, err.to_string()))
+    }
+}
+
+// Deserializes the parameters matched from the route template, e.g. {id} in /user/{id}
+pub struct Path<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Path<T> {
+    fn from_request(request: &HttpRequest) -> Result<Self, Rejection> {
+        from_pairs(request.path_params.iter().map(|p| (p.0.as_str(), p.1.as_str())))
+            .map(Path)
+            .map_err(|err| Rejection::new(StatusCode::BAD_REQUEST, err.to_string()))
+    }
+}
+
+// Deserializes an application/x-www-form-urlencoded body
+pub struct Form<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Form<T> {
+    fn from_request(request: &HttpRequest) -> Result<Self, Rejection> {
+        if media_type(request).as_deref() != Some("application/x-www-form-urlencoded") {
+            return Err(Rejection::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, "Expected a form Content-Type"));
+        }
+        serde_urlencoded::from_bytes(&request.body)
+            .map(Form)
+            .map_err(|err| Rejection::new(StatusCode::UNPROCESSABLE_ENTITY, err.to_string()))
+    }
+}
+
+// Deserializes the headers, header names are lowercased so fields should be named accordingly,
+// e.g. #[serde(rename = "x-request-id")]
+pub struct Header<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Header<T> {
+    fn from_request(request: &HttpRequest) -> Result<Self, Rejection> {
+        let mut headers: Vec<(String, &str)> = Vec::new();
+        for (name, value) in &request.headers {
+            let name = name.to_ascii_lowercase();
+            if !headers.iter().any(|h| h.0 == name) {
+                headers.push((name, value));
+            }
+        }
+        from_pairs(headers.iter().map(|h| (h.0.as_str(), h.1)))
+            .map(Header)
+            .map_err(|err| Rejection::new(StatusCode::BAD_REQUEST, err.to_string()))
+    }
+}
+
+// The shared state of type S registered with HttpServerBuilder::with_state
+pub struct State<S>(pub Arc<S>);
+
+impl<S: Send + Sync + 'static> FromRequest for State<S> {
+    fn from_request(request: &HttpRequest) -> Result<Self, Rejection> {
+        request.state::<S>()
+            .map(State)
+            .ok_or_else(|| Rejection::new(StatusCode::INTERNAL_SERVER_ERROR, "Missing server state"))
+    }
+}
+
+macro_rules! tuple_from_request {
+    ($($extractor:ident),+) => {
+        impl<$($extractor: FromRequest),+> FromRequest for ($($extractor,)+) {
+            fn from_request(request: &HttpRequest) -> Result<Self, Rejection> {
+                Ok(($($extractor::from_request(request)?,)+))
+            }
+        }
+    };
+}
+
+tuple_from_request!(A);
+tuple_from_request!(A, B);
+tuple_from_request!(A, B, C);
+tuple_from_request!(A, B, C, D);
+
+// A RouteHandler that extracts its typed input from the request before calling the function. A
+// failed extraction is answered with the rejection and the function is not called.
+pub struct Typed<E, F> {
+    handler: F,
+    extractor: PhantomData<fn() -> E>,
+}
+
+pub fn typed<E, F, R>(handler: F) -> Typed<E, F>
+where E: FromRequest, F: Fn(E) -> R + Send + Sync, R: IntoResponse {
+    Typed {
+        handler,
+        extractor: PhantomData,
+    }
+}
+
+impl<E, F, R> RouteHandler for Typed<E, F>
+where E: FromRequest, F: Fn(E) -> R + Send + Sync, R: IntoResponse {
+    fn respond(&self, request: HttpRequest) -> HttpResponse {
+        match E::from_request(&request) {
+            Ok(input) => (self.handler)(input).into_response(),
+            Err(rejection) => rejection.into(),
+        }
+    }
+}
+
+// the Content-Type without its parameters, e.g. application/json for application/json; charset=utf-8
+fn media_type(request: &HttpRequest) -> Option<String> {
+    request.header("Content-Type")
+        .and_then(|c| c.split(';').next())
+        .map(|c| c.trim().to_ascii_lowercase())
+}
+
+// urlencoded values are parsed into numbers, booleans etc. by serde_urlencoded, which plain
+// string maps cannot do
+fn from_pairs<'a, T, I>(pairs: I) -> Result<T, serde_urlencoded::de::Error>
+where T: DeserializeOwned, I: Iterator<Item = (&'a str, &'a str)> {
+    let pairs: Vec<(&str, &str)> = pairs.collect();
+    let encoded = serde_urlencoded::to_string(pairs)
+        .map_err(|err| serde::de::Error::custom(err.to_string()))?;
+    serde_urlencoded::from_str(&encoded)
+}
+
+#[allow(dead_code)]
+#[derive(serde::Deserialize)]
+struct TestInput {
+    id: u64,
+}
+
+#[allow(dead_code)]
+fn test_request(content_type: &str, body: &str) -> HttpRequest {
+    let request = format!(
+        "POST /input?id=3 HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}\r\nX-Id: 4\r\n\r\n{}",
+        content_type, body.len(), body
+    );
+    super::request::read_http_request(&mut request.as_bytes()).unwrap()
+}
+
+#[test]
+fn extracts_json() {
+    let Json(input) = test_request("application/json", "{\"id\": 1}").extract::<Json<TestInput>>().unwrap();
+    assert_eq!(input.id, 1);
+    let Json(input) = test_request("application/problem+json", "{\"id\": 2}").extract::<Json<TestInput>>().unwrap();
+    assert_eq!(input.id, 2);
+}
+
+#[test]
+fn rejects_json_with_status() {
+    let status = |r: Result<Json<TestInput>, Rejection>| r.err().unwrap().status_code;
+    assert_eq!(status(test_request("text/plain", "{\"id\": 1}").extract()), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert_eq!(status(test_request("application/jsonp", "{\"id\": 1}").extract()), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert_eq!(status(test_request("text/x-application/json-foo", "{\"id\": 1}").extract()), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert_eq!(status(test_request("application/json", "{\"id\": ").extract()), StatusCode::BAD_REQUEST);
+    assert_eq!(status(test_request("application/json", "{\"id\": \"x\"}").extract()), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[test]
+fn extracts_query_form_and_header() {
+    let request = test_request("application/x-www-form-urlencoded; charset=utf-8", "id=2");
+    let (Query(query), Form(form), Header(header)) =
+        request.extract::<(Query<TestInput>, Form<TestInput>, Header<XIdHeader>)>().unwrap();
+    assert_eq!(query.id, 3);
+    assert_eq!(form.id, 2);
+    assert_eq!(header.id, 4);
+}
+
+#[allow(dead_code)]
+#[derive(serde::Deserialize)]
+struct XIdHeader {
+    #[serde(rename = "x-id")]
+    id: u64,
+}
+
+#[test]
+fn extracts_typed_path() {
+    let mut request = test_request("text/plain", "");
+    request.path_params.insert("id".to_string(), "5".to_string());
+    let Path(input) = request.extract::<Path<TestInput>>().unwrap();
+    assert_eq!(input.id, 5);
+    request.path_params.insert("id".to_string(), "five".to_string());
+    assert_eq!(request.extract::<Path<TestInput>>().err().unwrap().status_code, StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn path_values_arrive_decoded() {
+    #[derive(serde::Deserialize)]
+    struct Name {
+        name: String,
+    }
+    let mut request = super::request::read_http_request(&mut "GET / HTTP/1.1\r\n\r\n".as_bytes()).unwrap();
+    request.path_params.insert(String::from("name"), String::from("a b+c"));
+    let Path(Name { name }) = Path::from_request(&request).ok().unwrap();
+    assert_eq!(name, "a b+c");
+}
@@ -7,6 +7,7 @@ pub enum HttpMethod {
     POST,
     PUT,
     DELETE,
+    OPTIONS,
 }
 
 impl fmt::Display for HttpMethod {
@@ -16,12 +17,14 @@ impl fmt::Display for HttpMethod {
             HttpMethod::POST => "POST",
             HttpMethod::PUT => "PUT",
             HttpMethod::DELETE => "DELETE",
+            HttpMethod::OPTIONS => "OPTIONS",
         })
     }
 }
 
 #[derive(Debug)]
 #[derive(PartialEq)]
+#[derive(Clone, Copy)]
 pub enum HttpVersion {
     Http10,
     Http11,
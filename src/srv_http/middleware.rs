@@ -0,0 +1,100 @@
+use std::sync::Arc;
+
+use super::{
+    request::HttpRequest,
+    response::HttpResponse,
+    service::RouteHandler,
+};
+
+// A middleware wraps the invocation of a RouteHandler. Implement before and after for simple
+// hooks, returning a response from before skips everything further down the chain. Implement
+// handle instead to get full control over when the rest of the chain runs.
+pub trait Middleware: Sync + Send {
+    fn before(&self, _request: &mut HttpRequest) -> Option<HttpResponse> {
+        None
+    }
+
+    fn after(&self, _response: &mut HttpResponse) {}
+
+    fn handle(&self, mut request: HttpRequest, next: Next) -> HttpResponse {
+        if let Some(response) = self.before(&mut request) {
+            return response;
+        }
+        let mut response = next.run(request);
+        self.after(&mut response);
+        response
+    }
+}
+
+// The remainder of a middleware chain, ending in the route handler
+pub struct Next<'a> {
+    middlewares: &'a [Arc<dyn Middleware>],
+    handler: &'a dyn RouteHandler,
+}
+
+impl<'a> Next<'a> {
+    pub fn new(middlewares: &'a [Arc<dyn Middleware>], handler: &'a dyn RouteHandler) -> Self {
+        Next {
+            middlewares,
+            handler,
+        }
+    }
+
+    pub fn run(self, request: HttpRequest) -> HttpResponse {
+        match self.middlewares.split_first() {
+            Some((middleware, rest)) => middleware.handle(request, Next::new(rest, self.handler)),
+            None => self.handler.respond(request),
+        }
+    }
+}
+
+// adds an X-Tag header with its name to every response, shared with the server_builder tests
+#[cfg(test)]
+pub(crate) struct TagMiddleware(pub(crate) &'static str);
+
+#[cfg(test)]
+impl Middleware for TagMiddleware {
+    fn after(&self, response: &mut HttpResponse) {
+        response.add_header(String::from("X-Tag"), String::from(self.0));
+    }
+}
+
+#[allow(dead_code)]
+struct RejectMiddleware;
+
+impl Middleware for RejectMiddleware {
+    fn before(&self, _: &mut HttpRequest) -> Option<HttpResponse> {
+        Some(HttpResponse::new(http::StatusCode::UNAUTHORIZED))
+    }
+}
+
+#[allow(dead_code)]
+struct OkHandler;
+
+impl RouteHandler for OkHandler {
+    fn respond(&self, _: HttpRequest) -> HttpResponse {
+        HttpResponse::new(http::StatusCode::OK)
+    }
+}
+
+#[allow(dead_code)]
+fn test_request() -> HttpRequest {
+    super::request::read_http_request(&mut "GET / HTTP/1.1\r\n\r\n".as_bytes()).unwrap()
+}
+
+#[test]
+fn runs_middlewares_outermost_first() {
+    let middlewares: Vec<Arc<dyn Middleware>> = vec![Arc::new(TagMiddleware("outer")), Arc::new(TagMiddleware("inner"))];
+    let response = Next::new(&middlewares, &OkHandler).run(test_request());
+    assert_eq!(response.status_code, http::StatusCode::OK);
+    let tags: Vec<&str> = response.headers.iter().map(|h| h.1.as_str()).collect();
+    assert_eq!(tags, vec!["inner", "outer"]);
+}
+
+#[test]
+fn short_circuits_chain() {
+    let middlewares: Vec<Arc<dyn Middleware>> = vec![Arc::new(TagMiddleware("outer")), Arc::new(RejectMiddleware)];
+    let response = Next::new(&middlewares, &OkHandler).run(test_request());
+    assert_eq!(response.status_code, http::StatusCode::UNAUTHORIZED);
+    assert_eq!(response.headers.len(), 1);
+}
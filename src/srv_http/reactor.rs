@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::mem;
+use std::net;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, Sender};
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token, Waker};
+
+use super::request::{HttpRequest, ParseError, RequestDecoder, RequestLimits};
+use super::server::{HttpServer, Responder};
+use super::timeout::Timeouts;
+
+const WAKER: Token = Token(usize::MAX);
+const READ_BUFFER_SIZE: usize = 8 * 1024;
+// a connection reads at most this much before the others get their turn
+const MAX_READ_PASS: usize = 64 * 1024;
+// waiting connections are checked against their timeouts this often
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// A connection owned by the reactor while it waits for a complete request. The decoder keeps
+// what is parsed of the request so far, the buffer the bytes it could not take yet, e.g. those
+// read past the end of a request for the next one.
+struct Connection {
+    token: Token,
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    decoder: RequestDecoder,
+    last_active: Instant,
+    request_started: Instant,
+}
+
+impl Connection {
+    fn new(token: Token, stream: TcpStream, buffer: Vec<u8>, limits: RequestLimits) -> Self {
+        Connection {
+            token,
+            stream,
+            buffer,
+            decoder: RequestDecoder::new(limits),
+            last_active: Instant::now(),
+            request_started: Instant::now(),
+        }
+    }
+
+    // between two requests, nothing of the next one has arrived
+    fn is_idle(&self) -> bool {
+        self.buffer.is_empty() && self.decoder.is_reading_head()
+    }
+
+    fn receive(&mut self, bytes: &[u8]) {
+        if self.is_idle() {
+            self.request_started = Instant::now();
+        }
+        self.buffer.extend_from_slice(bytes);
+        self.last_active = Instant::now();
+    }
+
+    // An idle connection is held to the keep-alive timeout, a request to the same timeouts as in
+    // the threaded mode
+    fn is_expired(&self, timeouts: &Timeouts) -> bool {
+        let stalled = self.last_active.elapsed();
+        if self.is_idle() {
+            stalled >= timeouts.keep_alive
+        } else if self.decoder.is_reading_head() {
+            stalled >= timeouts.header_read || self.request_started.elapsed() >= timeouts.request_head
+        } else {
+            stalled >= timeouts.body_read
+        }
+    }
+}
+
+struct Reactor<'a> {
+    server: &'a HttpServer,
+    responder: Responder,
+    poll: Poll,
+    waker: Arc<Waker>,
+    listeners: Vec<TcpListener>,
+    connections: HashMap<Token, Connection>,
+    next_token: usize,
+    returned_sender: Sender<Connection>,
+    returned: Receiver<Connection>,
+    // connections that stopped reading at MAX_READ_PASS, the poll will not report them again
+    pending: Vec<Token>,
+}
+
+// Serves every listener of the server from the calling thread until a shutdown is requested.
+// Sockets are polled without blocking, a worker is only taken once a request has fully arrived
+// and the connection comes back to the reactor after the response is written.
+pub(super) fn run(server: &HttpServer) {
+    let poll = Poll::new().expect("Unable to create the reactor");
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER).expect("Unable to create the reactor waker"));
+    let (returned_sender, returned) = crossbeam_channel::unbounded();
+    let mut reactor = Reactor {
+        server,
+        responder: server.responder(),
+        poll,
+        waker,
+        listeners: Vec::new(),
+        connections: HashMap::new(),
+        next_token: 0,
+        returned_sender,
+        returned,
+        pending: Vec::new(),
+    };
+
+    for listener in &server.listeners {
+        let listener = listener
+            .try_clone()
+            .and_then(|l| l.set_nonblocking(true).map(|_| l))
+            .map(TcpListener::from_std);
+        match listener {
+            Ok(mut listener) => {
+                // listeners take the first tokens, connections are numbered after them
+                let token = Token(reactor.listeners.len());
+                if let Err(err) = reactor.poll.registry().register(&mut listener, token, Interest::READABLE) {
+                    println!("Unable to register listener: {}", err);
+                    continue
+                }
+                if let Ok(address) = listener.local_addr() {
+                    println!("Now listening on {}", address);
+                }
+                reactor.listeners.push(listener);
+            }
+            Err(err) => println!("Unable to register listener: {}", err),
+        }
+    }
+
+    reactor.next_token = reactor.listeners.len();
+    reactor.run();
+}
+
+impl Reactor<'_> {
+    fn run(&mut self) {
+        let mut events = Events::with_capacity(1024);
+        let mut last_sweep = Instant::now();
+        while !self.server.shutdown.is_requested() {
+            let timeout = if self.pending.is_empty() { SWEEP_INTERVAL } else { Duration::ZERO };
+            if let Err(err) = self.poll.poll(&mut events, Some(timeout)) {
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue
+                }
+                println!("Reactor poll failure: {}", err);
+                break
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    WAKER => self.take_returned(),
+                    token if token.0 < self.listeners.len() => self.accept(token.0),
+                    token => {
+                        if let Some(connection) = self.connections.remove(&token) {
+                            self.process(connection);
+                        }
+                    }
+                }
+            }
+
+            for token in mem::take(&mut self.pending) {
+                if let Some(connection) = self.connections.remove(&token) {
+                    self.process(connection);
+                }
+            }
+
+            if last_sweep.elapsed() >= SWEEP_INTERVAL {
+                self.close_expired();
+                last_sweep = Instant::now();
+            }
+        }
+        // idle connections are closed when the reactor drops them, connections still at a worker
+        // are closed by the worker once the reactor is gone
+        println!("Reactor stopped; closing {} idle connections.", self.connections.len());
+    }
+
+    fn next_token(&mut self) -> Token {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        token
+    }
+
+    fn accept(&mut self, listener: usize) {
+        loop {
+            match self.listeners[listener].accept() {
+                Ok((mut stream, _)) => {
+                    let token = self.next_token();
+                    if self.poll.registry().register(&mut stream, token, Interest::READABLE).is_err() {
+                        continue
+                    }
+                    let connection = Connection::new(token, stream, Vec::new(), self.server.request_limits);
+                    self.connections.insert(token, connection);
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    println!("Stream IO Failure: {}", err);
+                    break
+                }
+            }
+        }
+    }
+
+    // Connections come back from the workers once their response is written. They are processed
+    // right away, the next request may have arrived while they were away.
+    fn take_returned(&mut self) {
+        while let Ok(mut connection) = self.returned.try_recv() {
+            let registered = self.poll.registry().register(&mut connection.stream, connection.token, Interest::READABLE);
+            if registered.is_ok() {
+                self.process(connection);
+            }
+        }
+    }
+
+    // Reads what arrived and hands a complete request to the worker pool. A pass stops after
+    // MAX_READ_PASS bytes and continues on the next turn of the loop, so a client sending without
+    // pause cannot hold up the others. Connections still waiting for input go back into the map,
+    // closed ones are dropped.
+    fn process(&mut self, mut connection: Connection) {
+        let mut buf = [0; READ_BUFFER_SIZE];
+        let mut read = 0;
+        loop {
+            // bytes left over from a pipelined request are decoded before reading more
+            match connection.decoder.decode(&mut connection.buffer) {
+                Ok(Some(request)) => return self.dispatch(connection, request),
+                Ok(None) => (),
+                Err(err) => {
+                    println!("{}", err);
+                    self.responder.reject(&err, &mut connection.stream);
+                    return
+                }
+            }
+            if read >= MAX_READ_PASS {
+                self.pending.push(connection.token);
+                self.connections.insert(connection.token, connection);
+                return
+            }
+
+            match connection.stream.read(&mut buf) {
+                // the client closed its side, a partial request goes with the connection
+                Ok(0) => return,
+                Ok(n) => {
+                    connection.receive(&buf[..n]);
+                    read += n;
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    self.connections.insert(connection.token, connection);
+                    return
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(_) => return,
+            }
+        }
+    }
+
+    fn dispatch(&mut self, mut connection: Connection, request: HttpRequest) {
+        let _ = self.poll.registry().deregister(&mut connection.stream);
+        let mut stream = net::TcpStream::from(connection.stream);
+        if self.server.worker_pool.is_full() {
+            stream = match self.server.shed_load(stream) {
+                Some(s) => s,
+                None => return,
+            };
+        }
+
+        let responder = self.responder.clone();
+        let write_timeout = self.server.write_timeout;
+        let returned_sender = self.returned_sender.clone();
+        let waker = self.waker.clone();
+        let token = connection.token;
+        let buffer = connection.buffer;
+        let limits = self.server.request_limits;
+        self.server.worker_pool.execute(move || {
+            // the worker writes the response itself, so a streamed body is not buffered in full
+            if stream.set_nonblocking(false).is_err() || stream.set_write_timeout(Some(write_timeout)).is_err() {
+                return
+            }
+            if !responder.respond(request, &mut &stream) || stream.set_nonblocking(true).is_err() {
+                return
+            }
+            let connection = Connection::new(token, TcpStream::from_std(stream), buffer, limits);
+            if returned_sender.send(connection).is_ok() {
+                let _ = waker.wake();
+            }
+        });
+    }
+
+    // Idle connections are closed quietly, a request that did not arrive in time is answered with
+    // 408 Request Timeout
+    fn close_expired(&mut self) {
+        let timeouts = self.server.timeouts();
+        let expired: Vec<Token> = self.connections
+            .iter()
+            .filter(|(_, c)| c.is_expired(&timeouts))
+            .map(|(token, _)| *token)
+            .collect();
+        for token in expired {
+            if let Some(mut connection) = self.connections.remove(&token) {
+                if !connection.is_idle() {
+                    self.responder.reject(&ParseError::Incomplete, &mut connection.stream);
+                }
+            }
+        }
+    }
+}
+
+#[allow(dead_code)]
+fn exchange(address: net::SocketAddr, request: &[u8]) -> String {
+    use std::io::Write;
+    let mut stream = net::TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(request).unwrap();
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+#[test]
+fn serves_requests_event_driven() {
+    use super::http_constants::HttpMethod;
+    use super::response::HttpResponse;
+    use super::server_builder::{ConnectionMode, HttpServerBuilder};
+    use super::service::Route;
+    use http::StatusCode;
+    let server = HttpServerBuilder::new()
+        .bind("127.0.0.1:0")
+        .connection_mode(ConnectionMode::EventDriven)
+        .worker_pool_limit(2)
+        .header_read_timeout(Duration::from_millis(100))
+        .add_route(Route::new("/", HttpMethod::GET, |_| StatusCode::OK))
+        .add_route(Route::new("/echo", HttpMethod::POST, |request: HttpRequest| {
+            HttpResponse::new(StatusCode::OK).bytes("application/octet-stream", request.body)
+        }))
+        .build()
+        .unwrap();
+    let address = server.listeners[0].local_addr().unwrap();
+    let handle = server.handle();
+    let running = std::thread::spawn(move || server.run());
+
+    // the connection comes back from the worker for the second request
+    let pipelined = exchange(address, b"GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert_eq!(pipelined.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+
+    // more than one read pass
+    let body = "x".repeat(3 * MAX_READ_PASS);
+    let request = format!("POST /echo HTTP/1.1\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+    let echoed = exchange(address, request.as_bytes());
+    assert!(echoed.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(echoed.ends_with(&body));
+
+    let stalled = exchange(address, b"GET / HTTP/1.1\r\nHost: ");
+    assert!(stalled.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    let bare_line_feeds = exchange(address, b"GET / HTTP/1.1\nConnection: close\n\n");
+    assert!(bare_line_feeds.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+    handle.shutdown();
+    running.join().unwrap();
+}
@@ -1,10 +1,17 @@
 use core::fmt;
-use std::io;
-use std::io::prelude::*;
+use std::collections::HashMap;
+use std::io::{self, BufRead, Read};
+use std::str::{self, FromStr, Utf8Error};
+use std::sync::Arc;
+
+use http::StatusCode;
+use serde::de::DeserializeOwned;
 
 use crate::debug;
 
 use super::http_constants::{HttpMethod, HttpVersion};
+use super::response::{HttpResponse, IntoResponse};
+use super::state::AppState;
 
 #[derive(Debug)]
 pub enum ParseError {
@@ -14,31 +21,150 @@ pub enum ParseError {
     Uri,
     Headers,
     Body,
+    RequestLineTooLong,
+    HeadersTooLarge,
+    BodyTooLarge,
+    TransferEncoding,
+    Incomplete,
+    ConnectionClosed,
+    Io(io::Error),
 }
 
 impl std::error::Error for ParseError {}
 
+impl ParseError {
+    // The status a client is answered with when its request cannot be read. None when there is
+    // nobody left to answer and the connection is closed silently.
+    pub fn status_code(&self) -> Option<StatusCode> {
+        Some(match self {
+            ParseError::RequestLine | ParseError::Uri | ParseError::Headers | ParseError::Body => StatusCode::BAD_REQUEST,
+            ParseError::Method | ParseError::TransferEncoding => StatusCode::NOT_IMPLEMENTED,
+            ParseError::Version => StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+            ParseError::RequestLineTooLong => StatusCode::URI_TOO_LONG,
+            ParseError::HeadersTooLarge => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            ParseError::BodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            // only reported once a read timed out
+            ParseError::Incomplete => StatusCode::REQUEST_TIMEOUT,
+            ParseError::ConnectionClosed | ParseError::Io(_) => return None,
+        })
+    }
+}
+
 impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", match &self {
             ParseError::RequestLine =>"Invalid Request Line",
-            ParseError::Method =>"Invalid Method",
-            ParseError::Version => "Invalid Version",
+            ParseError::Method =>"Unsupported Method",
+            ParseError::Version => "Unsupported Version",
             ParseError::Uri => "Invalid Uri",
             ParseError::Headers => "Invalid Headers",
             ParseError::Body => "Invalid Body",
+            ParseError::RequestLineTooLong => "Request Line Too Long",
+            ParseError::HeadersTooLarge => "Headers Too Large",
+            ParseError::BodyTooLarge => "Body Too Large",
+            ParseError::TransferEncoding => "Unsupported Transfer Encoding",
+            ParseError::Incomplete => "Incomplete Request",
+            ParseError::ConnectionClosed => "Connection Closed",
+            ParseError::Io(err) => return write!(f, "Connection Failure: {}", err),
         })
     }
 }
 
-#[derive(Debug)]
 pub struct HttpRequest {
     pub method: HttpMethod,
     pub uri: String,
     pub version: HttpVersion,
     pub headers: Vec<(String, String)>,
-    pub body: String,
+    pub trailers: Vec<(String, String)>,
+    pub body: Vec<u8>,
     pub query_params: Vec<(String, String)>,
+    pub path_params: HashMap<String, String>,
+    pub state: Arc<AppState>,
+}
+
+// bodies can be megabytes, debug output only shows the start of them
+const DEBUG_BODY_PREVIEW: usize = 64;
+
+impl fmt::Debug for HttpRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let preview = String::from_utf8_lossy(&self.body[..self.body.len().min(DEBUG_BODY_PREVIEW)]);
+        f.debug_struct("HttpRequest")
+            .field("method", &self.method)
+            .field("uri", &self.uri)
+            .field("version", &self.version)
+            .field("headers", &self.headers)
+            .field("trailers", &self.trailers)
+            .field("body", &format_args!("{} bytes {:?}", self.body.len(), preview))
+            .field("query_params", &self.query_params)
+            .field("path_params", &self.path_params)
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub enum ParamError {
+    Missing(String),
+    Invalid(String),
+}
+
+impl std::error::Error for ParamError {}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self {
+            ParamError::Missing(name) => write!(f, "Missing path parameter {}", name),
+            ParamError::Invalid(name) => write!(f, "Invalid path parameter {}", name),
+        }
+    }
+}
+
+impl From<ParamError> for HttpResponse {
+    fn from(_: ParamError) -> Self {
+        HttpResponse::new(StatusCode::BAD_REQUEST)
+    }
+}
+
+impl IntoResponse for ParamError {
+    fn into_response(self) -> HttpResponse {
+        self.into()
+    }
+}
+
+impl HttpRequest {
+    pub fn param<T: FromStr>(&self, name: &str) -> Result<T, ParamError> {
+        let value = self.path_params
+            .get(name)
+            .ok_or_else(|| ParamError::Missing(name.to_string()))?;
+        value.parse::<T>().map_err(|_| ParamError::Invalid(name.to_string()))
+    }
+
+    pub fn state<S: Send + Sync + 'static>(&self) -> Option<Arc<S>> {
+        self.state.get::<S>()
+    }
+
+    pub fn text(&self) -> Result<&str, Utf8Error> {
+        str::from_utf8(&self.body)
+    }
+
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        serde_json::from_slice(&self.body)
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|h| h.0.eq_ignore_ascii_case(name))
+            .map(|h| h.1.as_str())
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        match self.header("Connection") {
+            Some(c) if c.eq_ignore_ascii_case("close") => false,
+            Some(c) if c.eq_ignore_ascii_case("keep-alive") => true,
+            _ => self.version == HttpVersion::Http11,
+        }
+    }
 }
 
 impl fmt::Display for HttpRequest {
@@ -47,7 +173,7 @@ impl fmt::Display for HttpRequest {
         for header in &self.headers {
             write!(f, "{}: {}\r\n", header.0, header.1)?;
         }
-        write!(f, "{}", &self.body)
+        write!(f, "{}", String::from_utf8_lossy(&self.body))
     }
 }
 
@@ -59,6 +185,7 @@ fn parse_http_method(method: &str) -> Result<HttpMethod, ParseError> {
         "POST" => Ok(HttpMethod::POST),
         "PUT" => Ok(HttpMethod::PUT),
         "DELETE" => Ok(HttpMethod::DELETE),
+        "OPTIONS" => Ok(HttpMethod::OPTIONS),
         _ => Err(ParseError::Method)
     }
 }
@@ -67,7 +194,9 @@ fn parse_http_version(version: &str) -> Result<HttpVersion, ParseError> {
     match version {
         "HTTP/1.0" => Ok(HttpVersion::Http10),
         "HTTP/1.1" => Ok(HttpVersion::Http11),
-        _ => Err(ParseError::Version)
+        // a well formed version we do not speak, anything else is not HTTP at all
+        v if v.starts_with("HTTP/") => Err(ParseError::Version),
+        _ => Err(ParseError::RequestLine)
     }
 }
 
@@ -82,23 +211,81 @@ fn is_valid_uri(uri: &str) -> bool {
     is_valid
 }
 
-pub fn read_http_request<T>(stream: &mut T) -> Result<HttpRequest, ParseError>
-where T: std::io::Read {
-    let mut buf_reader = io::BufReader::new(stream);
+// Upper bounds on the parts of a request. Each mode parses a request as it arrives and rejects it
+// once a limit is crossed, so a connection holds at most a read buffer beyond these. A body grows
+// as it is read, not by the length the client announces.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestLimits {
+    pub max_request_line: usize,
+    pub max_headers: usize,
+    pub max_header_bytes: usize,
+    pub max_body_size: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        RequestLimits {
+            max_request_line: 8 * 1024,
+            max_headers: 100,
+            max_header_bytes: 16 * 1024,
+            max_body_size: 10 * 1024 * 1024,
+        }
+    }
+}
+
+// chunk extensions make the size line of a chunk arbitrarily long, this is plenty for any real one
+const MAX_CHUNK_SIZE_LINE: usize = 1024;
+
+// The reader is kept by the caller so that bytes of pipelined requests buffered after this
+// request are not lost between calls
+pub fn read_http_request<T>(buf_reader: &mut T) -> Result<HttpRequest, ParseError>
+where T: io::BufRead {
+    read_limited_request(buf_reader, &RequestLimits::default())
+}
+
+pub(crate) fn read_limited_request<T>(buf_reader: &mut T, limits: &RequestLimits) -> Result<HttpRequest, ParseError>
+where T: io::BufRead {
+    let head = read_request_head(buf_reader, limits)?;
+    read_request_body(buf_reader, head, limits)
+}
+
+// How the body of a request is delimited, decided from its headers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum BodyFraming {
+    Empty,
+    Length(usize),
+    Chunked,
+}
+
+// The request line and headers of a request whose body is not read yet. Connections read the
+// head and the body separately to give each their own timeout.
+pub(crate) struct RequestHead {
+    pub request: HttpRequest,
+    pub framing: BodyFraming,
+}
 
+pub(crate) fn read_request_head<T>(buf_reader: &mut T, limits: &RequestLimits) -> Result<RequestHead, ParseError>
+where T: io::BufRead {
     let mut request_line = String::new();
-    buf_reader.read_line(&mut request_line).map_err(|_| ParseError::RequestLine)?;
+    match read_line_limited(buf_reader, &mut request_line, limits.max_request_line) {
+        Ok(Some(0)) => return Err(ParseError::ConnectionClosed),
+        Ok(Some(_)) => (),
+        Ok(None) => return Err(ParseError::RequestLineTooLong),
+        // nothing of a next request arrived before the read timed out
+        Err(err) if request_line.is_empty() && is_timeout(&err) => return Err(ParseError::ConnectionClosed),
+        Err(err) => return Err(read_error(err, ParseError::RequestLine)),
+    }
 
     debug!(&request_line);
     let mut request_line_parts = request_line.split_whitespace();
-    let method = request_line_parts.next().ok_or(ParseError::Method)?;
+    let method = request_line_parts.next().ok_or(ParseError::RequestLine)?;
     let path = request_line_parts.next().ok_or(ParseError::Uri)?;
 
     if !is_valid_uri(path) {
         return Err(ParseError::Uri);
     }
 
-    let version = request_line_parts.next().ok_or(ParseError::Version)?;
+    let version = request_line_parts.next().ok_or(ParseError::RequestLine)?;
 
     // parse uri and query parameters
     let mut uri_parts = path.split("?");
@@ -110,57 +297,420 @@ where T: std::io::Read {
         for q in qp.split("&") {
             let mut qsplit = q.split("=");
             let qp_name = qsplit.next().ok_or(ParseError::Uri)?;
-            let qp_value = match qsplit.next() {
-                Some(v) => v,
-                None => "",
-            };
+            let qp_value = qsplit.next().unwrap_or_default();
             query_params.push((qp_name.to_string(), qp_value.to_string()));
         }
     }
 
     // read headers
     let mut headers: Vec<(String, String)> = Vec::new();
-    let mut content_length: usize = 0;
+    let mut content_length: Option<usize> = None;
+    let mut transfer_encoding: Option<String> = None;
+    let mut header_bytes = 0;
     let mut line: String;
 
     loop {
         line = String::new();
-        buf_reader.read_line(&mut line).map_err(|_| ParseError::Headers)?;
+        header_bytes += read_line_limited(buf_reader, &mut line, limits.max_header_bytes - header_bytes)
+            .map_err(|e| read_error(e, ParseError::Headers))?
+            .ok_or(ParseError::HeadersTooLarge)?;
         if line == "\r\n" {
             break;
         }
+        if headers.len() == limits.max_headers {
+            return Err(ParseError::HeadersTooLarge);
+        }
 
-        let mut header_parts = line.trim_end().splitn(2, ": ");
-        let header_name = String::from(header_parts.next().ok_or(ParseError::Headers)?);
-        let header_value = String::from(header_parts.next().ok_or(ParseError::Headers)?);
+        let (header_name, header_value) = parse_header_line(&line)?;
 
-        if header_name == "Content-Length" {
-            if let Ok(len) = header_value.parse::<usize>() {
-                content_length = len;
+        if header_name.eq_ignore_ascii_case("Content-Length") {
+            let len = parse_length(&header_value, 10).ok_or(ParseError::Headers)?;
+            if content_length.is_some_and(|l| l != len) {
+                return Err(ParseError::Headers);
             }
+            content_length = Some(len);
+        } else if header_name.eq_ignore_ascii_case("Transfer-Encoding") {
+            transfer_encoding = Some(match transfer_encoding {
+                Some(te) => concat_string!(te, ", ", header_value),
+                None => header_value.clone(),
+            });
         }
 
         headers.push((header_name, header_value));
     }
 
-    // read_body
-    let mut body = vec![0; content_length];
-    if content_length > 0 {
-        buf_reader.read_exact(&mut body).map_err(|_| ParseError::Body)?;
-    }
+    let framing = match (transfer_encoding, content_length) {
+        // a request framed both ways can be read differently by a proxy in front of us
+        (Some(_), Some(_)) => return Err(ParseError::Headers),
+        (Some(te), None) => {
+            // chunked has to be the last coding and the only one, we decode no other
+            let codings: Vec<&str> = te.split(',').map(str::trim).filter(|c| !c.is_empty()).collect();
+            let is_chunked = |coding: &&str| coding.eq_ignore_ascii_case("chunked");
+            if !codings.last().is_some_and(is_chunked) {
+                return Err(ParseError::Headers);
+            }
+            if !codings.iter().all(is_chunked) {
+                return Err(ParseError::TransferEncoding);
+            }
+            if codings.len() > 1 {
+                return Err(ParseError::Headers);
+            }
+            BodyFraming::Chunked
+        }
+        (None, Some(len)) if len > limits.max_body_size => return Err(ParseError::BodyTooLarge),
+        (None, Some(len)) => BodyFraming::Length(len),
+        (None, None) => BodyFraming::Empty,
+    };
 
     let request = HttpRequest {
         method: parse_http_method(method)?,
         uri: String::from(target),
         version: parse_http_version(version)?,
         headers,
-        body: String::from_utf8(body).map_err(|_| ParseError::Body)?,
+        trailers: Vec::new(),
+        body: Vec::new(),
         query_params,
+        path_params: HashMap::new(),
+        state: Arc::default(),
+    };
+
+    Ok(RequestHead {
+        request,
+        framing,
+    })
+}
+
+pub(crate) fn read_request_body<T>(buf_reader: &mut T, head: RequestHead, limits: &RequestLimits) -> Result<HttpRequest, ParseError>
+where T: io::BufRead {
+    let mut request = head.request;
+    request.body = match head.framing {
+        BodyFraming::Empty => Vec::new(),
+        BodyFraming::Length(len) => {
+            let mut body = Vec::new();
+            read_body_bytes(buf_reader, &mut body, len)?;
+            body
+        }
+        BodyFraming::Chunked => read_chunked_body(buf_reader, &mut request.trailers, limits)?,
     };
 
     Ok(request)
 }
 
+// Reads a line of at most limit bytes, a longer line is reported as None without reading the rest
+fn read_line_limited<T>(buf_reader: &mut T, line: &mut String, limit: usize) -> io::Result<Option<usize>>
+where T: io::BufRead {
+    let read = buf_reader.take((limit as u64).saturating_add(1)).read_line(line)?;
+    Ok(if read > limit { None } else { Some(read) })
+}
+
+// Lengths are digits only. Parsing alone would also take a sign, e.g. +5, which a proxy in front of
+// us may read differently and frame the request another way.
+fn parse_length(value: &str, radix: u32) -> Option<usize> {
+    if value.is_empty() || !value.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    usize::from_str_radix(value, radix).ok()
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+// A read that timed out or ran out of buffered input leaves the request incomplete, the rest of
+// it may still arrive. Text that is not UTF-8 is the client's fault, any other error is the
+// connection's.
+fn read_error(err: io::Error, otherwise: ParseError) -> ParseError {
+    match err.kind() {
+        _ if is_timeout(&err) => ParseError::Incomplete,
+        io::ErrorKind::InvalidData => otherwise,
+        _ => ParseError::Io(err),
+    }
+}
+
+fn parse_header_line(line: &str) -> Result<(String, String), ParseError> {
+    let mut header_parts = line.trim_end().splitn(2, ':');
+    let header_name = header_parts.next().ok_or(ParseError::Headers)?;
+    let header_value = header_parts.next().ok_or(ParseError::Headers)?;
+    if header_name.is_empty() || header_name.contains(char::is_whitespace) {
+        return Err(ParseError::Headers);
+    }
+
+    Ok((String::from(header_name), String::from(header_value.trim())))
+}
+
+fn read_chunked_body<T>(buf_reader: &mut T, trailers: &mut Vec<(String, String)>, limits: &RequestLimits) -> Result<Vec<u8>, ParseError>
+where T: io::BufRead {
+    let mut body: Vec<u8> = Vec::new();
+
+    loop {
+        let mut size_line = String::new();
+        read_line_limited(buf_reader, &mut size_line, MAX_CHUNK_SIZE_LINE)
+            .map_err(|e| read_error(e, ParseError::Body))?
+            .ok_or(ParseError::Body)?;
+        let size = parse_chunk_size(&size_line)?;
+        if size == 0 {
+            break;
+        }
+        if body.len().saturating_add(size) > limits.max_body_size {
+            return Err(ParseError::BodyTooLarge);
+        }
+
+        read_body_bytes(buf_reader, &mut body, size)?;
+
+        let mut crlf = [0; 2];
+        buf_reader.read_exact(&mut crlf).map_err(|e| read_error(e, ParseError::Body))?;
+        if &crlf != b"\r\n" {
+            return Err(ParseError::Body);
+        }
+    }
+
+    // trailers are held to the same limits as the headers
+    let mut trailer_bytes = 0;
+    loop {
+        let mut line = String::new();
+        trailer_bytes += read_line_limited(buf_reader, &mut line, limits.max_header_bytes - trailer_bytes)
+            .map_err(|e| read_error(e, ParseError::Body))?
+            .ok_or(ParseError::HeadersTooLarge)?;
+        if line == "\r\n" {
+            break;
+        }
+        if trailers.len() == limits.max_headers {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        trailers.push(parse_header_line(&line)?);
+    }
+
+    Ok(body)
+}
+
+// Appends len bytes to the body as they are read, a client announcing more than it sends costs no
+// memory up front
+fn read_body_bytes<T>(buf_reader: &mut T, body: &mut Vec<u8>, len: usize) -> Result<(), ParseError>
+where T: io::BufRead {
+    let read = buf_reader.take(len as u64).read_to_end(body).map_err(|e| read_error(e, ParseError::Body))?;
+    if read < len {
+        return Err(ParseError::Io(io::Error::from(io::ErrorKind::UnexpectedEof)));
+    }
+    Ok(())
+}
+
+fn parse_chunk_size(size_line: &str) -> Result<usize, ParseError> {
+    if !size_line.ends_with("\r\n") {
+        return Err(ParseError::Body);
+    }
+
+    // chunk extensions are allowed after the size but carry nothing we use
+    let size = size_line.trim_end().split(';').next().ok_or(ParseError::Body)?.trim();
+    parse_length(size, 16).ok_or(ParseError::Body)
+}
+
+// Decodes requests from the bytes a non-blocking connection has received so far. Decoded bytes are
+// taken out of the buffer and the parsed head and body position are kept between calls, so every
+// byte is looked at once and the buffer never holds much more than the limits allow.
+pub(crate) struct RequestDecoder {
+    limits: RequestLimits,
+    state: DecodeState,
+}
+
+enum DecodeState {
+    // how much of the buffer is known not to hold the end of the head
+    Head { scanned: usize },
+    Body { request: HttpRequest, body: BodyState },
+}
+
+enum BodyState {
+    // the bytes still to come
+    Length(usize),
+    ChunkSize,
+    ChunkData(usize),
+    ChunkEnd,
+    // the bytes of trailers read so far
+    Trailers(usize),
+}
+
+impl RequestDecoder {
+    pub fn new(limits: RequestLimits) -> Self {
+        RequestDecoder {
+            limits,
+            state: DecodeState::Head { scanned: 0 },
+        }
+    }
+
+    // whether the head of the next request is still being read
+    pub fn is_reading_head(&self) -> bool {
+        matches!(self.state, DecodeState::Head { .. })
+    }
+
+    // Takes what it can from the buffer, the request once it is complete. Bytes after it, e.g. of a
+    // pipelined request, stay in the buffer for the next call.
+    pub fn decode(&mut self, buffer: &mut Vec<u8>) -> Result<Option<HttpRequest>, ParseError> {
+        loop {
+            let decoded = match &mut self.state {
+                DecodeState::Head { scanned } => {
+                    let Some(head) = decode_head(buffer, scanned, &self.limits)? else {
+                        return Ok(None);
+                    };
+                    let body = match head.framing {
+                        BodyFraming::Empty => BodyState::Length(0),
+                        BodyFraming::Length(len) => BodyState::Length(len),
+                        BodyFraming::Chunked => BodyState::ChunkSize,
+                    };
+                    self.state = DecodeState::Body { request: head.request, body };
+                    continue;
+                }
+                DecodeState::Body { request, body } => decode_body(buffer, request, body, &self.limits)?,
+            };
+            if !decoded {
+                return Ok(None);
+            }
+
+            let DecodeState::Body { request, .. } = std::mem::replace(&mut self.state, DecodeState::Head { scanned: 0 }) else {
+                unreachable!();
+            };
+            return Ok(Some(request));
+        }
+    }
+}
+
+// Parses the head once its blank line has arrived. Without one, the buffer may grow up to the
+// limits of the request line and headers, beyond that the head is parsed anyway to find the limit
+// that was crossed.
+fn decode_head(buffer: &mut Vec<u8>, scanned: &mut usize, limits: &RequestLimits) -> Result<Option<RequestHead>, ParseError> {
+    let start = scanned.saturating_sub(2);
+    let Some(end) = find_head_end(&buffer[start..]) else {
+        *scanned = buffer.len();
+        if buffer.len() <= limits.max_request_line.saturating_add(limits.max_header_bytes) {
+            return Ok(None);
+        }
+        return match read_request_head(&mut BufferedInput::new(buffer), limits) {
+            Err(ParseError::Incomplete) | Ok(_) => Err(ParseError::HeadersTooLarge),
+            Err(err) => Err(err),
+        };
+    };
+
+    let mut input = BufferedInput::new(&buffer[..start + end]);
+    let head = read_request_head(&mut input, limits)?;
+    let position = input.position();
+    buffer.drain(..position);
+    Ok(Some(head))
+}
+
+// The end of a line followed by an empty one. A bare \n as the empty line ends the head as well,
+// read_request_head then rejects it just like on a blocking connection.
+fn find_head_end(buffer: &[u8]) -> Option<usize> {
+    (0..buffer.len()).find_map(|i| match &buffer[i..] {
+        [b'\n', b'\n', ..] => Some(i + 2),
+        [b'\n', b'\r', b'\n', ..] => Some(i + 3),
+        _ => None,
+    })
+}
+
+// Moves what has arrived of the body into the request, true once all of it is there
+fn decode_body(buffer: &mut Vec<u8>, request: &mut HttpRequest, state: &mut BodyState, limits: &RequestLimits) -> Result<bool, ParseError> {
+    loop {
+        match state {
+            BodyState::Length(remaining) | BodyState::ChunkData(remaining) => {
+                let available = (*remaining).min(buffer.len());
+                request.body.extend(buffer.drain(..available));
+                *remaining -= available;
+                if *remaining > 0 {
+                    return Ok(false);
+                }
+                if let BodyState::Length(_) = state {
+                    return Ok(true);
+                }
+                *state = BodyState::ChunkEnd;
+            }
+            BodyState::ChunkSize => {
+                let Some(line) = take_line(buffer, MAX_CHUNK_SIZE_LINE, ParseError::Body)? else {
+                    return Ok(false);
+                };
+                let size = parse_chunk_size(&line)?;
+                if request.body.len().saturating_add(size) > limits.max_body_size {
+                    return Err(ParseError::BodyTooLarge);
+                }
+                *state = if size == 0 { BodyState::Trailers(0) } else { BodyState::ChunkData(size) };
+            }
+            BodyState::ChunkEnd => {
+                if buffer.len() < 2 {
+                    return Ok(false);
+                }
+                if &buffer[..2] != b"\r\n" {
+                    return Err(ParseError::Body);
+                }
+                buffer.drain(..2);
+                *state = BodyState::ChunkSize;
+            }
+            BodyState::Trailers(trailer_bytes) => {
+                // trailers are held to the same limits as the headers
+                let limit = limits.max_header_bytes - *trailer_bytes;
+                let Some(line) = take_line(buffer, limit, ParseError::HeadersTooLarge)? else {
+                    return Ok(false);
+                };
+                *trailer_bytes += line.len();
+                if line == "\r\n" {
+                    return Ok(true);
+                }
+                if request.trailers.len() == limits.max_headers {
+                    return Err(ParseError::HeadersTooLarge);
+                }
+                request.trailers.push(parse_header_line(&line)?);
+            }
+        }
+    }
+}
+
+// Takes a line of at most limit bytes from the buffer, a longer one is the given error
+fn take_line(buffer: &mut Vec<u8>, limit: usize, too_long: ParseError) -> Result<Option<String>, ParseError> {
+    let Some(end) = buffer.iter().take(limit).position(|&b| b == b'\n') else {
+        return if buffer.len() >= limit { Err(too_long) } else { Ok(None) };
+    };
+    let line = buffer.drain(..=end).collect();
+    String::from_utf8(line).map(Some).map_err(|_| ParseError::Body)
+}
+
+// Bytes buffered from a non-blocking connection as a reader that reports WouldBlock once they run
+// out, so read_http_request can tell an incomplete request apart from a malformed one
+pub(crate) struct BufferedInput<'a> {
+    buffer: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BufferedInput<'a> {
+    pub fn new(buffer: &'a [u8]) -> Self {
+        BufferedInput {
+            buffer,
+            position: 0,
+        }
+    }
+
+    // the number of bytes the parsed request took up
+    pub fn position(&self) -> usize {
+        self.position
+    }
+}
+
+impl Read for BufferedInput<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.fill_buf()?.read(buf)?;
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for BufferedInput<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.position == self.buffer.len() {
+            return Err(io::Error::from(io::ErrorKind::WouldBlock));
+        }
+        Ok(&self.buffer[self.position..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.position += amount;
+    }
+}
+
 #[allow(dead_code)]
 const TEST_REQUEST: &str = "GET /request?qp1=1&qp2=2 HTTP/1.1\r\n\
 header1: header1\r\n\
@@ -213,6 +763,289 @@ fn parses_headers() {
 fn parses_body() {
     let http_request = read_http_request(&mut TEST_REQUEST.as_bytes()).unwrap();
     let expected = "<body>some_body</body>";
-    assert_eq!(http_request.body, expected);
+    assert_eq!(http_request.text().unwrap(), expected);
+}
+
+#[test]
+fn parses_binary_body() {
+    let mut request = b"POST /upload HTTP/1.1\r\nContent-Length: 4\r\n\r\n".to_vec();
+    request.extend_from_slice(&[0xff, 0x00, 0xfe, 0x01]);
+    let http_request = read_http_request(&mut request.as_slice()).unwrap();
+    assert_eq!(http_request.body, vec![0xff, 0x00, 0xfe, 0x01]);
+    assert!(http_request.text().is_err());
+}
+
+#[test]
+fn parses_json_body() {
+    let request = "POST /animal HTTP/1.1\r\nContent-Length: 9\r\n\r\n{\"id\": 1}";
+    let http_request = read_http_request(&mut request.as_bytes()).unwrap();
+    let json: serde_json::Value = http_request.json().unwrap();
+    assert_eq!(json["id"], 1);
+}
+
+#[test]
+fn parses_typed_path_param() {
+    let mut http_request = read_http_request(&mut TEST_REQUEST.as_bytes()).unwrap();
+    http_request.path_params.insert("id".to_string(), "42".to_string());
+    assert_eq!(http_request.param::<u64>("id").unwrap(), 42);
+}
+
+#[test]
+fn rejects_invalid_path_param() {
+    let mut http_request = read_http_request(&mut TEST_REQUEST.as_bytes()).unwrap();
+    http_request.path_params.insert("id".to_string(), "abc".to_string());
+    assert!(matches!(http_request.param::<u64>("id"), Err(ParamError::Invalid(_))));
+    assert!(matches!(http_request.param::<u64>("name"), Err(ParamError::Missing(_))));
 }
 
+#[test]
+fn parses_pipelined_requests() {
+    let pipelined = "GET /first HTTP/1.1\r\n\r\nGET /second HTTP/1.1\r\nConnection: close\r\n\r\n";
+    let mut reader = pipelined.as_bytes();
+    let first = read_http_request(&mut reader).unwrap();
+    let second = read_http_request(&mut reader).unwrap();
+    assert_eq!(first.uri, "/first");
+    assert!(first.keep_alive());
+    assert_eq!(second.uri, "/second");
+    assert!(!second.keep_alive());
+    assert!(matches!(read_http_request(&mut reader), Err(ParseError::ConnectionClosed)));
+}
+
+#[test]
+fn http10_defaults_to_close() {
+    let http_request = read_http_request(&mut "GET / HTTP/1.0\r\n\r\n".as_bytes()).unwrap();
+    assert!(!http_request.keep_alive());
+    let http_request = read_http_request(&mut "GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n".as_bytes()).unwrap();
+    assert!(http_request.keep_alive());
+}
+
+#[allow(dead_code)]
+const TEST_CHUNKED_REQUEST: &str = "POST /upload HTTP/1.1\r\n\
+Transfer-Encoding: chunked\r\n\
+\r\n\
+7\r\n\
+<body>s\r\n\
+f;name=value\r\n\
+ome_body</body>\r\n\
+0\r\n\
+Expires: never\r\n\
+\r\n";
+
+#[test]
+fn parses_chunked_body() {
+    let http_request = read_http_request(&mut TEST_CHUNKED_REQUEST.as_bytes()).unwrap();
+    assert_eq!(http_request.text().unwrap(), "<body>some_body</body>");
+}
+
+#[test]
+fn parses_chunked_trailers() {
+    let http_request = read_http_request(&mut TEST_CHUNKED_REQUEST.as_bytes()).unwrap();
+    assert_eq!(http_request.trailers, vec![("Expires".to_string(), "never".to_string())]);
+}
+
+#[test]
+fn leaves_pipelined_request_after_chunked_body() {
+    let pipelined = concat_string!(TEST_CHUNKED_REQUEST, "GET /next HTTP/1.1\r\n\r\n");
+    let mut reader = pipelined.as_bytes();
+    read_http_request(&mut reader).unwrap();
+    assert_eq!(read_http_request(&mut reader).unwrap().uri, "/next");
+}
+
+#[test]
+fn rejects_content_length_with_transfer_encoding() {
+    let request = "POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+    assert!(matches!(read_http_request(&mut request.as_bytes()), Err(ParseError::Headers)));
+}
+
+#[test]
+fn rejects_unsupported_transfer_encoding() {
+    let request = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n";
+    assert!(matches!(read_http_request(&mut request.as_bytes()), Err(ParseError::Headers)));
+    let request = "POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n0\r\n\r\n";
+    let result = read_http_request(&mut request.as_bytes());
+    assert_eq!(result.err().and_then(|e| e.status_code()), Some(StatusCode::NOT_IMPLEMENTED));
+    let request = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+    assert!(matches!(read_http_request(&mut request.as_bytes()), Err(ParseError::Headers)));
+    let request = "POST / HTTP/1.1\r\nTransfer-Encoding: Chunked\r\n\r\n0\r\n\r\n";
+    assert!(read_http_request(&mut request.as_bytes()).is_ok());
+}
+
+#[test]
+fn rejects_signed_lengths() {
+    let request = "POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\nhello";
+    assert!(matches!(read_http_request(&mut request.as_bytes()), Err(ParseError::Headers)));
+    let request = "POST / HTTP/1.1\r\nContent-Length: -0\r\n\r\n";
+    assert!(matches!(read_http_request(&mut request.as_bytes()), Err(ParseError::Headers)));
+    let request = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n+a\r\n0123456789\r\n0\r\n\r\n";
+    assert!(matches!(read_http_request(&mut request.as_bytes()), Err(ParseError::Body)));
+    let request = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\na\r\n0123456789\r\n0\r\n\r\n";
+    assert!(read_http_request(&mut request.as_bytes()).is_ok());
+}
+
+#[test]
+fn rejects_truncated_body() {
+    let request = "POST / HTTP/1.1\r\nContent-Length: 1000000\r\n\r\nhello";
+    assert!(matches!(read_http_request(&mut request.as_bytes()), Err(ParseError::Io(_))));
+    let request = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nfffff\r\nhello";
+    assert!(matches!(read_http_request(&mut request.as_bytes()), Err(ParseError::Io(_))));
+}
+
+#[test]
+fn rejects_malformed_chunk_size() {
+    let request = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n\r\n";
+    assert!(matches!(read_http_request(&mut request.as_bytes()), Err(ParseError::Body)));
+}
+
+#[test]
+fn buffered_input_reports_incomplete_request() {
+    let partial = b"POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhel";
+    let mut input = BufferedInput::new(partial);
+    assert!(matches!(read_http_request(&mut input), Err(ParseError::Incomplete)));
+
+    let complete = b"GET / HTTP/1.1\r\n\r\nGET /next";
+    let mut input = BufferedInput::new(complete);
+    assert!(read_http_request(&mut input).is_ok());
+    assert_eq!(input.position(), 18);
+    assert!(matches!(read_http_request(&mut input), Err(ParseError::Incomplete)));
+}
+
+#[test]
+fn decodes_request_fed_byte_by_byte() {
+    let input = b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n1;ext\r\n!\r\n0\r\nX-Sum: 6\r\n\r\nGET /next";
+    let mut decoder = RequestDecoder::new(RequestLimits::default());
+    let mut buffer = Vec::new();
+    let mut decoded = None;
+    for (i, byte) in input.iter().enumerate() {
+        buffer.push(*byte);
+        if let Some(request) = decoder.decode(&mut buffer).unwrap() {
+            decoded = Some((i, request));
+            break;
+        }
+    }
+
+    let (end, request) = decoded.unwrap();
+    assert_eq!(end, input.len() - "GET /next".len() - 1);
+    assert_eq!(request.body, b"hello!");
+    assert_eq!(request.trailers, vec![("X-Sum".to_string(), "6".to_string())]);
+    assert!(buffer.is_empty());
+    assert!(decoder.is_reading_head());
+}
+
+#[test]
+fn decoder_rejects_bare_line_feeds_like_reader() {
+    let request = b"GET / HTTP/1.1\nConnection: close\n\n";
+    assert!(matches!(read_http_request(&mut &request[..]), Err(ParseError::Headers)));
+    let mut buffer = request.to_vec();
+    let result = RequestDecoder::new(RequestLimits::default()).decode(&mut buffer);
+    assert!(matches!(result, Err(ParseError::Headers)));
+}
+
+#[test]
+fn decodes_pipelined_requests() {
+    let mut buffer = b"POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcGET /next HTTP/1.1\r\n\r\nGET".to_vec();
+    let mut decoder = RequestDecoder::new(RequestLimits::default());
+    assert_eq!(decoder.decode(&mut buffer).unwrap().unwrap().body, b"abc");
+    assert_eq!(decoder.decode(&mut buffer).unwrap().unwrap().uri, "/next");
+    assert!(decoder.decode(&mut buffer).unwrap().is_none());
+    assert_eq!(buffer, b"GET");
+}
+
+#[test]
+fn decoder_holds_request_to_limits() {
+    let mut decoder = RequestDecoder::new(TEST_LIMITS);
+    let mut buffer = b"GET / HTTP/1.1\r\n".to_vec();
+    buffer.resize(TEST_LIMITS.max_request_line + TEST_LIMITS.max_header_bytes, b'a');
+    assert!(decoder.decode(&mut buffer).unwrap().is_none());
+    buffer.push(b'a');
+    assert!(matches!(decoder.decode(&mut buffer), Err(ParseError::HeadersTooLarge)));
+
+    let mut decoder = RequestDecoder::new(TEST_LIMITS);
+    let mut buffer = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\n12345\r\n".to_vec();
+    assert!(decoder.decode(&mut buffer).unwrap().is_none());
+    assert!(buffer.is_empty());
+    buffer.extend_from_slice(b"4\r\n");
+    assert!(matches!(decoder.decode(&mut buffer), Err(ParseError::BodyTooLarge)));
+
+    let mut decoder = RequestDecoder::new(TEST_LIMITS);
+    let mut buffer = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+    buffer.resize(buffer.len() + MAX_CHUNK_SIZE_LINE, b'0');
+    assert!(matches!(decoder.decode(&mut buffer), Err(ParseError::Body)));
+}
+
+#[allow(dead_code)]
+const TEST_LIMITS: RequestLimits = RequestLimits {
+    max_request_line: 32,
+    max_headers: 2,
+    max_header_bytes: 64,
+    max_body_size: 8,
+};
+
+#[test]
+fn rejects_request_line_over_limit() {
+    let request = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(32));
+    let result = read_limited_request(&mut request.as_bytes(), &TEST_LIMITS);
+    assert!(matches!(result, Err(ParseError::RequestLineTooLong)));
+}
+
+#[test]
+fn rejects_headers_over_limit() {
+    let too_many = "GET / HTTP/1.1\r\na: 1\r\nb: 2\r\nc: 3\r\n\r\n";
+    let result = read_limited_request(&mut too_many.as_bytes(), &TEST_LIMITS);
+    assert!(matches!(result, Err(ParseError::HeadersTooLarge)));
+
+    let too_large = format!("GET / HTTP/1.1\r\na: {}\r\n\r\n", "1".repeat(64));
+    let result = read_limited_request(&mut too_large.as_bytes(), &TEST_LIMITS);
+    assert!(matches!(result, Err(ParseError::HeadersTooLarge)));
+}
+
+#[test]
+fn rejects_body_over_limit() {
+    let sized = "POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\n123456789";
+    let result = read_limited_request(&mut sized.as_bytes(), &TEST_LIMITS);
+    assert!(matches!(result, Err(ParseError::BodyTooLarge)));
+
+    let chunked = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\n12345\r\n4\r\n6789\r\n0\r\n\r\n";
+    let result = read_limited_request(&mut chunked.as_bytes(), &TEST_LIMITS);
+    assert!(matches!(result, Err(ParseError::BodyTooLarge)));
+
+    let within = "POST / HTTP/1.1\r\nContent-Length: 8\r\n\r\n12345678";
+    assert!(read_limited_request(&mut within.as_bytes(), &TEST_LIMITS).is_ok());
+}
+
+#[test]
+fn debug_output_previews_body() {
+    let body = "x".repeat(100_000);
+    let request = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+    let request = read_http_request(&mut request.as_bytes()).unwrap();
+    let debug = format!("{:#?}", request);
+    assert!(debug.contains(&format!("body: 100000 bytes \"{}\"", "x".repeat(DEBUG_BODY_PREVIEW))));
+    assert!(debug.len() < 1000);
+}
+
+#[test]
+fn reads_request_without_limits() {
+    let unlimited = RequestLimits {
+        max_request_line: usize::MAX,
+        max_headers: usize::MAX,
+        max_header_bytes: usize::MAX,
+        max_body_size: usize::MAX,
+    };
+    let request = "POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\nX-Sum: 5\r\n\r\n";
+    let parsed = read_limited_request(&mut request.as_bytes(), &unlimited).unwrap();
+    assert_eq!(parsed.body, b"hello");
+
+    let mut buffer = request.as_bytes().to_vec();
+    let decoded = RequestDecoder::new(unlimited).decode(&mut buffer).unwrap().unwrap();
+    assert_eq!(decoded.trailers.len(), 1);
+}
+
+#[test]
+fn maps_parse_errors_to_status() {
+    let status = |request: &str| read_http_request(&mut request.as_bytes()).err().unwrap().status_code();
+    assert_eq!(status("BREW / HTTP/1.1\r\n\r\n"), Some(StatusCode::NOT_IMPLEMENTED));
+    assert_eq!(status("GET / HTTP/2.0\r\n\r\n"), Some(StatusCode::HTTP_VERSION_NOT_SUPPORTED));
+    assert_eq!(status("GET / garbage\r\n\r\n"), Some(StatusCode::BAD_REQUEST));
+    assert_eq!(status("GET /\r\n\r\n"), Some(StatusCode::BAD_REQUEST));
+    assert_eq!(status(""), None);
+    assert_eq!(ParseError::Io(io::Error::from(io::ErrorKind::ConnectionReset)).status_code(), None);
+}
@@ -1,51 +1,225 @@
+use std::io::{self, Read, Write};
 use http::StatusCode;
 use serde::Serialize;
 use crate::debug;
 
+use super::http_constants::HttpVersion;
+
+const STREAM_BUFFER_SIZE: usize = 8 * 1024;
+
+pub enum Body {
+    Bytes(Vec<u8>),
+    Reader(Box<dyn Read + Send>, Option<u64>),
+    Chunks(Box<dyn Iterator<Item = Vec<u8>> + Send>),
+}
+
+impl Body {
+    pub fn from_reader<R: Read + Send + 'static>(reader: R) -> Self {
+        Body::Reader(Box::new(reader), None)
+    }
+
+    pub fn sized_reader<R: Read + Send + 'static>(reader: R, length: u64) -> Self {
+        Body::Reader(Box::new(reader), Some(length))
+    }
+
+    pub fn from_chunks<I>(chunks: I) -> Self
+    where I: IntoIterator<Item = Vec<u8>>, I::IntoIter: Send + 'static {
+        Body::Chunks(Box::new(chunks.into_iter()))
+    }
+
+    pub fn len(&self) -> Option<u64> {
+        match &self {
+            Body::Bytes(b) => Some(b.len() as u64),
+            Body::Reader(_, len) => *len,
+            Body::Chunks(_) => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+}
+
+impl Default for Body {
+    fn default() -> Self {
+        Body::Bytes(Vec::new())
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Self {
+        Body::Bytes(text.into_bytes())
+    }
+}
+
+impl From<&str> for Body {
+    fn from(text: &str) -> Self {
+        Body::Bytes(text.as_bytes().to_vec())
+    }
+}
+
+// Anything a route handler can answer with. Results are answered with either side, so handlers can
+// use ? on errors that convert into a response.
+pub trait IntoResponse {
+    fn into_response(self) -> HttpResponse;
+}
+
+impl IntoResponse for HttpResponse {
+    fn into_response(self) -> HttpResponse {
+        self
+    }
+}
+
+impl IntoResponse for StatusCode {
+    fn into_response(self) -> HttpResponse {
+        HttpResponse::new(self)
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> HttpResponse {
+        match self {
+            Ok(r) => r.into_response(),
+            Err(e) => e.into_response(),
+        }
+    }
+}
+
 pub struct HttpResponse {
     pub status_code: StatusCode,
-    pub body: String,
+    pub body: Body,
     pub headers: Vec<(String, String)>,
+    // whether every chunk of a streamed body is sent as soon as it is produced
+    pub flush_chunks: bool,
 }
 
 impl HttpResponse {
     pub fn new(status_code: StatusCode) -> Self {
         HttpResponse {
             status_code,
-            body: String::new(),
+            body: Body::default(),
             headers: Vec::new(),
+            flush_chunks: false,
         }
     }
 
-    pub fn build(self) -> String {
-        let response = concat_string!(
+    // Bodies without a known length are sent chunked to HTTP/1.1 clients. HTTP/1.0 clients do not
+    // understand chunked encoding, so the body is written as is and the connection has to be
+    // closed afterwards to mark its end
+    pub fn write_to<W: Write>(mut self, writer: &mut W, version: &HttpVersion) -> io::Result<()> {
+        // 1xx and 204 responses never have a body or its length. A 304 has no body either, its
+        // Content-Length is only sent when the handler set it.
+        let status = self.status_code;
+        let bodyless = status.is_informational() || status == StatusCode::NO_CONTENT || status == StatusCode::NOT_MODIFIED;
+        if bodyless {
+            self.body = Body::default();
+            if status != StatusCode::NOT_MODIFIED {
+                self.headers.retain(|h| !h.0.eq_ignore_ascii_case("Content-Length"));
+            }
+        }
+
+        let length = self.body.len();
+        let chunked = length.is_none() && *version == HttpVersion::Http11;
+        if let Some(len) = length.filter(|_| !bodyless) {
+            if !self.has_header("Content-Length") {
+                self.add_header(String::from("Content-Length"), len.to_string());
+            }
+        } else if chunked {
+            self.add_header(String::from("Transfer-Encoding"), String::from("chunked"));
+        }
+
+        let head = concat_string!(
             "HTTP/1.1 ",
             self.status_code.to_string(),
             "\r\n",
             self.build_headers(),
-            "\r\n",
-            self.body
+            "\r\n"
         );
-        debug!(&response);
-        response
+        debug!(&head);
+
+        let mut writer = io::BufWriter::with_capacity(STREAM_BUFFER_SIZE, writer);
+        writer.write_all(head.as_bytes())?;
+        match self.body {
+            Body::Bytes(bytes) => writer.write_all(&bytes)?,
+            Body::Reader(reader, Some(len)) => {
+                let copied = io::copy(&mut reader.take(len), &mut writer)?;
+                if copied < len {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+            }
+            Body::Reader(mut reader, None) => {
+                let mut buf = vec![0; STREAM_BUFFER_SIZE];
+                loop {
+                    let n = match reader.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(n) => n,
+                        Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(err) => return Err(err),
+                    };
+                    write_chunk(&mut writer, &buf[..n], chunked, self.flush_chunks)?;
+                }
+                if chunked {
+                    writer.write_all(b"0\r\n\r\n")?;
+                }
+            }
+            Body::Chunks(chunks) => {
+                for chunk in chunks.filter(|c| !c.is_empty()) {
+                    write_chunk(&mut writer, &chunk, chunked, self.flush_chunks)?;
+                }
+                if chunked {
+                    writer.write_all(b"0\r\n\r\n")?;
+                }
+            }
+        }
+        writer.flush()
     }
 
     pub fn json<T: Serialize>(mut self, obj: &T) -> Self {
         self.add_header(String::from("Content-Type"), String::from("application/json; charset=utf-8"));
-        match serde_json::to_string(obj) {
-            Ok(o) => self.body = o,
+        match serde_json::to_vec(obj) {
+            Ok(o) => self.body = Body::Bytes(o),
             Err(_) => self.status_code = StatusCode::INTERNAL_SERVER_ERROR,
         }
-        if !self.body.is_empty() {
-            self.add_header(String::from("Content-Length"), self.body.len().to_string());
-        }
         self
     }
 
-    fn add_header(&mut self, name: String, value: String) -> () {
+    pub fn text<S: Into<String>>(self, text: S) -> Self {
+        self.bytes("text/plain; charset=utf-8", text.into().into_bytes())
+    }
+
+    pub fn bytes<B: Into<Vec<u8>>>(mut self, content_type: &str, bytes: B) -> Self {
+        self.add_header(String::from("Content-Type"), String::from(content_type));
+        self.body = Body::Bytes(bytes.into());
+        self
+    }
+
+    pub fn body<B: Into<Body>>(mut self, body: B) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    // Streamed bodies are buffered and written in 8 KiB blocks. A producer that is slow to
+    // generate its chunks, e.g. one sending events as they happen, should flush each chunk so it
+    // reaches the client right away.
+    pub fn flush_each_chunk(mut self) -> Self {
+        self.flush_chunks = true;
+        self
+    }
+
+    pub fn add_header(&mut self, name: String, value: String) {
         self.headers.push((name, value));
     }
 
+    pub fn has_header(&self, name: &str) -> bool {
+        self.headers.iter().any(|h| h.0.eq_ignore_ascii_case(name))
+    }
+
     fn build_headers(&self) -> String {
         self.headers
             .iter()
@@ -53,3 +227,107 @@ impl HttpResponse {
             .collect()
     }
 }
+
+fn write_chunk<W: Write>(writer: &mut W, chunk: &[u8], chunked: bool, flush: bool) -> io::Result<()> {
+    if chunked {
+        write!(writer, "{:x}\r\n", chunk.len())?;
+        writer.write_all(chunk)?;
+        writer.write_all(b"\r\n")?;
+    } else {
+        writer.write_all(chunk)?;
+    }
+    if flush {
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn write_response(response: HttpResponse, version: &HttpVersion) -> String {
+    let mut out: Vec<u8> = Vec::new();
+    response.write_to(&mut out, version).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn writes_content_length_for_buffered_body() {
+    let response = HttpResponse::new(StatusCode::OK).body("hello");
+    let expected = "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+    assert_eq!(write_response(response, &HttpVersion::Http11), expected);
+}
+
+#[test]
+fn writes_binary_body_with_content_type() {
+    let response = HttpResponse::new(StatusCode::OK).bytes("application/octet-stream", vec![0xff, 0x00]);
+    let mut out: Vec<u8> = Vec::new();
+    response.write_to(&mut out, &HttpVersion::Http11).unwrap();
+    let mut expected = b"HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: 2\r\n\r\n".to_vec();
+    expected.extend_from_slice(&[0xff, 0x00]);
+    assert_eq!(out, expected);
+}
+
+#[test]
+fn writes_chunked_body_of_unknown_length() {
+    let chunks = vec![b"hello".to_vec(), Vec::new(), b" world".to_vec()];
+    let response = HttpResponse::new(StatusCode::OK).body(Body::from_chunks(chunks));
+    let expected = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+    assert_eq!(write_response(response, &HttpVersion::Http11), expected);
+}
+
+#[test]
+fn writes_raw_stream_to_http10_client() {
+    let response = HttpResponse::new(StatusCode::OK).body(Body::from_reader("hello".as_bytes()));
+    let expected = "HTTP/1.1 200 OK\r\n\r\nhello";
+    assert_eq!(write_response(response, &HttpVersion::Http10), expected);
+}
+
+#[allow(dead_code)]
+struct CountingWriter {
+    out: Vec<u8>,
+    writes: usize,
+}
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes += 1;
+        self.out.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn buffers_chunks_unless_flushed() {
+    let chunks = || (0..100).map(|i| i.to_string().into_bytes());
+    let mut writer = CountingWriter { out: Vec::new(), writes: 0 };
+    let response = HttpResponse::new(StatusCode::OK).body(Body::from_chunks(chunks()));
+    response.write_to(&mut writer, &HttpVersion::Http11).unwrap();
+    assert_eq!(writer.writes, 1);
+
+    let mut writer = CountingWriter { out: Vec::new(), writes: 0 };
+    let response = HttpResponse::new(StatusCode::OK).body(Body::from_chunks(chunks())).flush_each_chunk();
+    response.write_to(&mut writer, &HttpVersion::Http11).unwrap();
+    // one write per chunk and one for the last, empty chunk
+    assert_eq!(writer.writes, 101);
+    assert!(writer.out.ends_with(b"2\r\n99\r\n0\r\n\r\n"));
+}
+
+#[test]
+fn writes_no_body_for_bodyless_statuses() {
+    let write = |response: HttpResponse| {
+        let mut out: Vec<u8> = Vec::new();
+        response.write_to(&mut out, &HttpVersion::Http11).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    assert_eq!(write(HttpResponse::new(StatusCode::NO_CONTENT)), "HTTP/1.1 204 No Content\r\n\r\n");
+    let mut response = HttpResponse::new(StatusCode::NO_CONTENT).text("ignored");
+    response.add_header(String::from("Content-Length"), String::from("7"));
+    assert_eq!(write(response), "HTTP/1.1 204 No Content\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n");
+    assert_eq!(write(HttpResponse::new(StatusCode::CONTINUE)), "HTTP/1.1 100 Continue\r\n\r\n");
+    let chunks = HttpResponse::new(StatusCode::NOT_MODIFIED).body(Body::from_chunks(vec![b"a".to_vec()]));
+    assert_eq!(write(chunks), "HTTP/1.1 304 Not Modified\r\n\r\n");
+    assert_eq!(write(HttpResponse::new(StatusCode::OK)), "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+}
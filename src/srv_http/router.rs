@@ -0,0 +1,412 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+
+use super::service::ServerError;
+
+// A route template like /user/{id}/posts split into its path segments. A parameter has to span a
+// whole segment, the value it matches is never empty and never contains a slash. A parameter can
+// be constrained to a primitive type, {id:u64}, or to a regex matching the whole segment,
+// {slug:[a-z-]+}. A trailing catch-all, {*path}, matches the rest of the path, slashes included,
+// and takes no constraint.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteAddress {
+    pub uri_template: String,
+    pub uri_params: Vec<String>,
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Static(String),
+    Param(Constraint),
+    CatchAll,
+}
+
+// What a parameter segment accepts, constraints are told apart by the text they were written as
+#[derive(Debug, Clone)]
+enum Constraint {
+    Any,
+    Type(String, fn(&str) -> bool),
+    Pattern(String, Regex),
+}
+
+impl Constraint {
+    fn new(constraint: &str) -> Result<Self, String> {
+        if constraint.is_empty() {
+            return Ok(Constraint::Any);
+        }
+        if let Some(parses) = type_check(constraint) {
+            return Ok(Constraint::Type(String::from(constraint), parses));
+        }
+        match Regex::new(&concat_string!("^(?:", constraint, ")$")) {
+            Ok(regex) => Ok(Constraint::Pattern(String::from(constraint), regex)),
+            Err(err) => Err(concat_string!("invalid constraint ", constraint, ": ", err.to_string())),
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        match &self {
+            Constraint::Any => "",
+            Constraint::Type(t, _) => t,
+            Constraint::Pattern(p, _) => p,
+        }
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        match &self {
+            Constraint::Any => true,
+            Constraint::Type(_, parses) => parses(value),
+            Constraint::Pattern(_, regex) => regex.is_match(value),
+        }
+    }
+}
+
+impl PartialEq for Constraint {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+fn parses<T: FromStr>(value: &str) -> bool {
+    value.parse::<T>().is_ok()
+}
+
+fn type_check(name: &str) -> Option<fn(&str) -> bool> {
+    Some(match name {
+        "u8" => parses::<u8>,
+        "u16" => parses::<u16>,
+        "u32" => parses::<u32>,
+        "u64" => parses::<u64>,
+        "u128" => parses::<u128>,
+        "usize" => parses::<usize>,
+        "i8" => parses::<i8>,
+        "i16" => parses::<i16>,
+        "i32" => parses::<i32>,
+        "i64" => parses::<i64>,
+        "i128" => parses::<i128>,
+        "isize" => parses::<isize>,
+        "f32" => parses::<f32>,
+        "f64" => parses::<f64>,
+        "bool" => parses::<bool>,
+        _ => return None,
+    })
+}
+
+impl RouteAddress {
+    // Fails on a template the router cannot match, e.g. one with an invalid constraint regex
+    pub fn new(uri_template: String) -> Result<Self, ServerError> {
+        let mut uri_params: Vec<String> = Vec::new();
+        let mut segments: Vec<Segment> = Vec::new();
+        let invalid = |reason: &str| Err(ServerError::InvalidRoute(uri_template.clone(), String::from(reason)));
+        for segment in split_path(&uri_template) {
+            if segments.last() == Some(&Segment::CatchAll) {
+                return invalid("a catch-all parameter must be the last path segment");
+            }
+            match param_name(segment) {
+                Some(param) => {
+                    let (name, constraint) = param.split_once(':').unwrap_or((param, ""));
+                    match name.strip_prefix('*') {
+                        Some(_) if !constraint.is_empty() => {
+                            return invalid("a catch-all parameter cannot be constrained");
+                        }
+                        Some(name) => {
+                            uri_params.push(String::from(name));
+                            segments.push(Segment::CatchAll);
+                        }
+                        None => {
+                            let constraint = match Constraint::new(constraint) {
+                                Ok(c) => c,
+                                Err(reason) => return invalid(&reason),
+                            };
+                            uri_params.push(String::from(name));
+                            segments.push(Segment::Param(constraint));
+                        }
+                    }
+                }
+                None if segment.contains(['{', '}']) => {
+                    return invalid("route parameters must span a whole path segment");
+                }
+                None => segments.push(Segment::Static(String::from(segment))),
+            }
+        }
+
+        Ok(RouteAddress {
+            uri_template,
+            uri_params,
+            segments,
+        })
+    }
+}
+
+// Finds routes by walking a prefix tree of path segments, so a lookup costs the length of the
+// path instead of the number of routes. Paths are matched exactly: /animal does not match
+// /zoo/animal and / matches nothing but /. A static segment takes priority over a constrained
+// parameter, which takes priority over a plain one and that over a catch-all. The next one is
+// only tried when no route below the previous one matches the rest of the path.
+pub struct Router<T> {
+    root: Node,
+    routes: Vec<T>,
+    params: Vec<Vec<String>>,
+}
+
+#[derive(Default)]
+struct Node {
+    statics: HashMap<String, Node>,
+    // constrained parameters in the order they were registered, the unconstrained one last
+    params: Vec<(Constraint, Node)>,
+    catch_all: Option<Box<Node>>,
+    // indices of the routes ending here, in the order they were registered
+    routes: Vec<usize>,
+}
+
+impl Node {
+    fn insert(&mut self, segments: &[Segment], index: usize) {
+        match segments.split_first() {
+            None => self.routes.push(index),
+            Some((Segment::Static(s), rest)) => self.statics.entry(s.clone()).or_default().insert(rest, index),
+            Some((Segment::Param(constraint), rest)) => self.param_node(constraint).insert(rest, index),
+            Some((Segment::CatchAll, _)) => self.catch_all.get_or_insert_with(Default::default).routes.push(index),
+        }
+    }
+
+    fn param_node(&mut self, constraint: &Constraint) -> &mut Node {
+        let position = match self.params.iter().position(|p| p.0 == *constraint) {
+            Some(position) => position,
+            None => {
+                let position = match constraint {
+                    Constraint::Any => self.params.len(),
+                    _ => self.params.iter().position(|p| p.0 == Constraint::Any).unwrap_or(self.params.len()),
+                };
+                self.params.insert(position, (constraint.clone(), Node::default()));
+                position
+            }
+        };
+        &mut self.params[position].1
+    }
+
+    // path is what is left of the request path without its leading slash, None once it is used up
+    fn lookup<'p>(&self, path: Option<&'p str>, values: &mut Vec<&'p str>) -> Option<&Node> {
+        let Some(path) = path else {
+            return if self.routes.is_empty() { None } else { Some(self) };
+        };
+        let (segment, rest) = match path.split_once('/') {
+            Some((segment, rest)) => (segment, Some(rest)),
+            None => (path, None),
+        };
+        if let Some(node) = self.statics.get(segment).and_then(|n| n.lookup(rest, values)) {
+            return Some(node);
+        }
+        if !segment.is_empty() {
+            for (_, param) in self.params.iter().filter(|p| p.0.matches(segment)) {
+                values.push(segment);
+                if let Some(node) = param.lookup(rest, values) {
+                    return Some(node);
+                }
+                values.pop();
+            }
+        }
+        let catch_all = self.catch_all.as_deref().filter(|_| !path.is_empty())?;
+        values.push(path);
+        Some(catch_all)
+    }
+}
+
+impl<T> Router<T> {
+    pub fn new<A>(routes: Vec<T>, address: A) -> Self
+    where A: Fn(&T) -> &RouteAddress {
+        let mut root = Node::default();
+        let mut params = Vec::new();
+        for (index, route) in routes.iter().enumerate() {
+            let address = address(route);
+            root.insert(&address.segments, index);
+            params.push(address.uri_params.clone());
+        }
+
+        Router {
+            root,
+            routes,
+            params,
+        }
+    }
+
+    pub fn routes(&self) -> &[T] {
+        &self.routes
+    }
+
+    // None when no route matches the path, whatever its method
+    pub fn lookup<'r, 'p>(&'r self, path: &'p str) -> Option<Found<'r, 'p, T>> {
+        let mut values = Vec::new();
+        let node = self.root.lookup(Some(path.strip_prefix('/').unwrap_or(path)), &mut values)?;
+        Some(Found {
+            router: self,
+            indices: &node.routes,
+            values,
+        })
+    }
+}
+
+// The routes registered for a matched path along with the parameter values taken from it
+pub struct Found<'r, 'p, T> {
+    router: &'r Router<T>,
+    indices: &'r [usize],
+    values: Vec<&'p str>,
+}
+
+impl<'r, T> Found<'r, '_, T> {
+    pub fn routes(&self) -> impl Iterator<Item = &'r T> + '_ {
+        self.indices.iter().map(|i| &self.router.routes[*i])
+    }
+
+    // The first route accepted by the predicate, with the path parameters named as in its template.
+    // Constraints are matched against the path as sent, the values handed out are percent-decoded.
+    pub fn select<P>(&self, predicate: P) -> Option<(&'r T, HashMap<String, String>)>
+    where P: Fn(&T) -> bool {
+        let index = *self.indices.iter().find(|i| predicate(&self.router.routes[**i]))?;
+        let params = self.router.params[index]
+            .iter()
+            .cloned()
+            .zip(self.values.iter().map(|v| percent_decode_str(v).decode_utf8_lossy().into_owned()))
+            .collect();
+        Some((&self.router.routes[index], params))
+    }
+}
+
+// Whether two templates match the same paths, e.g. /user/{id} and /user/{name}. Parameters with
+// different constraints are not compared any further, a path the one rejects falls through to
+// the other.
+pub(crate) fn same_paths(template: &str, other: &str) -> bool {
+    let mut segments = split_path(template);
+    let mut other_segments = split_path(other);
+    loop {
+        match (segments.next(), other_segments.next()) {
+            (None, None) => return true,
+            (Some(s), Some(o)) if same_segment(s, o) => (),
+            _ => return false,
+        }
+    }
+}
+
+fn same_segment(segment: &str, other: &str) -> bool {
+    match (param_name(segment), param_name(other)) {
+        (Some(param), Some(other)) => param_kind(param) == param_kind(other),
+        (None, None) => segment == other,
+        _ => false,
+    }
+}
+
+// the constraint of a parameter, * for a catch-all
+fn param_kind(param: &str) -> &str {
+    if param.starts_with('*') {
+        "*"
+    } else {
+        param.split_once(':').map_or("", |p| p.1)
+    }
+}
+
+fn param_name(segment: &str) -> Option<&str> {
+    segment.strip_prefix('{').and_then(|s| s.strip_suffix('}'))
+}
+
+fn split_path(path: &str) -> std::str::Split<'_, char> {
+    path.strip_prefix('/').unwrap_or(path).split('/')
+}
+
+#[allow(dead_code)]
+fn test_router(templates: &[&str]) -> Router<RouteAddress> {
+    let routes = templates.iter().map(|t| RouteAddress::new(t.to_string()).unwrap()).collect();
+    Router::new(routes, |r| r)
+}
+
+#[allow(dead_code)]
+fn lookup_template(router: &Router<RouteAddress>, path: &str) -> Option<String> {
+    let found = router.lookup(path)?;
+    found.select(|_| true).map(|r| r.0.uri_template.clone())
+}
+
+#[test]
+fn matches_whole_path_only() {
+    let router = test_router(&["/", "/animal"]);
+    assert_eq!(lookup_template(&router, "/").as_deref(), Some("/"));
+    assert_eq!(lookup_template(&router, "/animal").as_deref(), Some("/animal"));
+    assert_eq!(lookup_template(&router, "/zoo/"), None);
+    assert_eq!(lookup_template(&router, "/zoo/animal"), None);
+    assert_eq!(lookup_template(&router, "/animal/"), None);
+    assert_eq!(lookup_template(&router, "/animals"), None);
+}
+
+#[test]
+fn prefers_static_segments_over_params() {
+    let router = test_router(&["/user/{id}", "/user/new", "/user/{id}/posts", "/user/new/edit"]);
+    assert_eq!(lookup_template(&router, "/user/new").as_deref(), Some("/user/new"));
+    assert_eq!(lookup_template(&router, "/user/7").as_deref(), Some("/user/{id}"));
+    assert_eq!(lookup_template(&router, "/user/new/edit").as_deref(), Some("/user/new/edit"));
+    // nothing below the static segment matches, so the parameter takes it
+    assert_eq!(lookup_template(&router, "/user/new/posts").as_deref(), Some("/user/{id}/posts"));
+    assert_eq!(lookup_template(&router, "/user/"), None);
+}
+
+#[test]
+fn extracts_params_by_name() {
+    let router = test_router(&["/user/{id}/posts/{post}"]);
+    let found = router.lookup("/user/7/posts/42").unwrap();
+    let (_, params) = found.select(|_| true).unwrap();
+    assert_eq!(params.get("id").map(String::as_str), Some("7"));
+    assert_eq!(params.get("post").map(String::as_str), Some("42"));
+}
+
+#[test]
+fn decodes_param_values() {
+    let router = test_router(&["/user/{id:[a-z%0-9]+}", "/files/{*path}"]);
+    let (_, params) = router.lookup("/user/a%20b").unwrap().select(|_| true).unwrap();
+    assert_eq!(params.get("id").map(String::as_str), Some("a b"));
+    let (_, params) = router.lookup("/files/my%2Fdocs/a+b%C3%A9").unwrap().select(|_| true).unwrap();
+    assert_eq!(params.get("path").map(String::as_str), Some("my/docs/a+bé"));
+}
+
+#[test]
+fn falls_through_constrained_params() {
+    let router = test_router(&["/post/{slug}", "/post/{id:u64}", "/post/{slug:[a-z-]+}/edit"]);
+    assert_eq!(lookup_template(&router, "/post/42").as_deref(), Some("/post/{id:u64}"));
+    assert_eq!(lookup_template(&router, "/post/hello-world").as_deref(), Some("/post/{slug}"));
+    assert_eq!(lookup_template(&router, "/post/hello-world/edit").as_deref(), Some("/post/{slug:[a-z-]+}/edit"));
+    assert_eq!(lookup_template(&router, "/post/Hello/edit"), None);
+    let found = router.lookup("/post/42").unwrap();
+    assert_eq!(found.select(|_| true).unwrap().1.get("id").map(String::as_str), Some("42"));
+}
+
+#[test]
+fn matches_rest_of_path_with_catch_all() {
+    let router = test_router(&["/files/{*path}", "/files/readme"]);
+    assert_eq!(lookup_template(&router, "/files/readme").as_deref(), Some("/files/readme"));
+    let found = router.lookup("/files/docs/guide.md").unwrap();
+    let (route, params) = found.select(|_| true).unwrap();
+    assert_eq!(route.uri_template, "/files/{*path}");
+    assert_eq!(params.get("path").map(String::as_str), Some("docs/guide.md"));
+    assert_eq!(lookup_template(&router, "/files/"), None);
+    assert_eq!(lookup_template(&router, "/files"), None);
+}
+
+#[test]
+fn compares_templates_by_constraint() {
+    assert!(same_paths("/user/{id}", "/user/{name}"));
+    assert!(same_paths("/user/{id:u64}", "/user/{key:u64}"));
+    assert!(same_paths("/files/{*path}", "/files/{*rest}"));
+    assert!(!same_paths("/user/{id:u64}", "/user/{name}"));
+    assert!(!same_paths("/user/{id}", "/user/{*rest}"));
+}
+
+#[test]
+fn rejects_invalid_templates() {
+    let reason = |template: &str| match RouteAddress::new(template.to_string()) {
+        Err(ServerError::InvalidRoute(_, reason)) => reason,
+        _ => String::new(),
+    };
+    assert!(reason("/user/{id:[0-9}").starts_with("invalid constraint [0-9"));
+    assert_eq!(reason("/files/{*path}/meta"), "a catch-all parameter must be the last path segment");
+    assert_eq!(reason("/files/{*path:u64}"), "a catch-all parameter cannot be constrained");
+    assert_eq!(reason("/user/id-{id}"), "route parameters must span a whole path segment");
+    assert!(RouteAddress::new("/user/{id:[0-9]+}/{*rest}".to_string()).is_ok());
+}
@@ -1,135 +1,491 @@
-use std::collections::HashMap;
-use std::io::Write;
+use std::io::{self, Write};
 use std::net;
-use std::sync::Arc;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use http::StatusCode;
-use regex::Regex;
 
 use crate::debug;
 
-use super::http_constants::HttpMethod;
-use super::request::{read_http_request, ParseError};
+use super::http_constants::{HttpMethod, HttpVersion};
+use super::request::{HttpRequest, ParseError, RequestLimits};
+use super::middleware::{Middleware, Next};
 use super::response::HttpResponse;
-use super::service::RouteHandler;
-use super::workpool::WorkerPool;
+use super::reactor;
+use super::router::{RouteAddress, Router};
+use super::server_builder::{ConnectionMode, OverloadPolicy};
+use super::server_handle::{ServerHandle, ShutdownState, StopGuard};
+use super::service::{ErrorRenderer, RouteHandler};
+use super::state::AppState;
+use super::timeout::{read_timed_request, wait_for_request, TimedReader, Timeouts};
+use super::workpool::{PoolStats, WorkerPool};
+
+// how long a rejected connection is read before it is closed
+const SHED_DRAIN_TIMEOUT: Duration = Duration::from_millis(100);
 
 pub struct HttpServer {
     pub listeners: Vec<net::TcpListener>,
-    pub routes: Arc<Vec<RegexRoute>>,
+    pub routes: Arc<Router<RegexRoute>>,
+    // the global middlewares, they are also part of every route's own chain
+    pub middlewares: Arc<Vec<Arc<dyn Middleware>>>,
     pub worker_pool: WorkerPool,
+    pub keep_alive_timeout: Duration,
+    pub header_read_timeout: Duration,
+    pub request_head_timeout: Duration,
+    pub body_read_timeout: Duration,
+    pub write_timeout: Duration,
+    pub request_limits: RequestLimits,
+    pub error_renderer: Arc<dyn ErrorRenderer>,
+    pub state: Arc<AppState>,
+    pub shutdown_timeout: Duration,
+    pub overload_policy: OverloadPolicy,
+    pub connection_mode: ConnectionMode,
+    pub(crate) shutdown: Arc<ShutdownState>,
+    // only held for its Drop
+    pub(crate) _stop_guard: StopGuard,
 }
 
-pub struct RouteAddress {
-    pub uri_template: String,
-    pub uri_regex: regex::Regex,
-    pub uri_params: Vec<String>,
+pub struct RegexRoute {
+    pub uri: RouteAddress,
+    pub method: HttpMethod,
+    pub handler: Arc<Box<dyn RouteHandler>>,
+    pub middlewares: Vec<Arc<dyn Middleware>>,
 }
 
-// I still need a good way to pass the extracted parameter values from a url like /user/{id} to the
-// http service, should I pass them in the function? I can also add them to the HTTP Request
-// object?
-impl RouteAddress {
-    pub fn new(uri_template: String) -> Self {
-        let mut uri_params: Vec<String> = Vec::new();
-
-        let mut url_param = String::new();
-        let mut is_url_param = false;
-        let mut regex_template = String::new();
-        for c in uri_template.chars() {
-            if c == '{' {
-                is_url_param = true;
-            } else if c == '}' {
-                uri_params.push(url_param);
-                url_param = String::new();
-                is_url_param = false;
-                regex_template += "([^/]+)";
-            } else if is_url_param {
-                url_param.push(c);
-            } else {
-                regex_template.push(c);
-            }
-        }
-        regex_template.push('$');
+impl HttpServer {
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle::new(self.shutdown.clone(), self.worker_pool.stats(), self.shutdown_timeout)
+    }
 
-        let uri_regex = Regex::new(format!(r"{}", &regex_template).as_str()).unwrap();
+    // Every listener gets its own acceptor thread, all feeding the same worker pool. In the event
+    // driven mode a single reactor thread serves all listeners instead. Blocks until the server is
+    // shut down through a ServerHandle and the in-flight requests are drained.
+    pub fn run(self) {
+        match self.connection_mode {
+            ConnectionMode::Threaded => self.run_acceptors(),
+            ConnectionMode::EventDriven => reactor::run(&self),
+        }
 
-        RouteAddress {
-            uri_template,
-            uri_regex,
-            uri_params,
+        println!("Stopped accepting connections; waiting for in-flight requests.");
+        if !self.worker_pool.shutdown(self.shutdown.timeout()) {
+            println!("Shutdown timed out; abandoning in-flight requests.");
         }
+        self.shutdown.set_stopped();
     }
 
-    pub fn is_match(&self, uri: &String) -> bool {
-        self.uri_regex.is_match(uri)
+    pub(super) fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            keep_alive: self.keep_alive_timeout,
+            header_read: self.header_read_timeout,
+            request_head: self.request_head_timeout,
+            body_read: self.body_read_timeout,
+            write: self.write_timeout,
+        }
     }
 
-    pub fn extract_uri_params(&self, uri: &String) -> Result<HashMap<String, String>, ParseError> {
-        let cap_groups = match self.uri_regex.captures(uri) {
-            Some(cg) => cg,
-            None => return Err(ParseError::Uri),
-        };
-        let mut uri_params_extracted: HashMap<String, String> = HashMap::new();
-        for (i, uri_param) in self.uri_params.iter().enumerate() {
-            let uri_param_value = match cap_groups.get(i + 1) {
-                Some(v) => v,
-                None => return Err(ParseError::Uri),
-            };
-            uri_params_extracted.insert(uri_param.clone(), uri_param_value.as_str().to_string());
+    pub(super) fn responder(&self) -> Responder {
+        Responder {
+            routes: self.routes.clone(),
+            middlewares: self.middlewares.clone(),
+            state: self.state.clone(),
+            shutdown: self.shutdown.clone(),
+            pool_stats: self.worker_pool.stats(),
+            error_renderer: self.error_renderer.clone(),
         }
-
-        Ok(uri_params_extracted)
     }
-}
 
-pub struct RegexRoute {
-    pub uri: RouteAddress,
-    pub method: HttpMethod,
-    pub handler: Arc<Box<dyn RouteHandler>>,
-}
+    fn run_acceptors(&self) {
+        thread::scope(|scope| {
+            for listener in &self.listeners {
+                let address = match listener.local_addr() {
+                    Ok(a) => a,
+                    Err(err) => {
+                        println!("Unable to read listener address: {}", err);
+                        continue
+                    }
+                };
+                thread::Builder::new()
+                    .name(format!("acceptor {}", address))
+                    .spawn_scoped(scope, move || self.accept(listener, address))
+                    .expect("Unable to spawn acceptor thread");
+            }
+        });
+    }
 
-impl HttpServer {
-    pub fn run(&self) {
-        for listener in &self.listeners {
-            println!("Now listening on {}", listener.local_addr().unwrap());
-            for stream in listener.incoming() {
-                match stream {
-                    Ok(s) => self.handle_connection(s),
-                    Err(err) => println!("Stream IO Failure: {}", err)
+    fn accept(&self, listener: &net::TcpListener, address: net::SocketAddr) {
+        println!("Now listening on {}", address);
+        for stream in listener.incoming() {
+            if self.shutdown.is_requested() {
+                break
+            }
+            match stream {
+                Ok(s) if self.worker_pool.is_full() => {
+                    if let Some(s) = self.shed_load(s) {
+                        self.handle_connection(s)
+                    }
                 }
+                Ok(s) => self.handle_connection(s),
+                Err(err) => println!("Stream IO Failure on {}: {}", address, err)
             }
         }
+        println!("Stopped listening on {}", address);
     }
 
-    fn handle_connection(&self, mut stream: net::TcpStream) -> () {
-        let routes_clone = self.routes.clone();
+    // Applies the overload policy to a connection that arrived while the worker queue is full.
+    // Gives the stream back when it should be queued anyway.
+    pub(super) fn shed_load(&self, mut stream: net::TcpStream) -> Option<net::TcpStream> {
+        match self.overload_policy {
+            OverloadPolicy::Block => return Some(stream),
+            OverloadPolicy::Reject { retry_after } => {
+                println!("Worker queue full; rejecting connection.");
+                // the acceptor writes the response itself, a slow client must not hold it up
+                let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+                let mut response = HttpResponse::new(StatusCode::SERVICE_UNAVAILABLE);
+                response.add_header(String::from("Retry-After"), retry_after.as_secs().to_string());
+                response.add_header(String::from("Connection"), String::from("close"));
+                let _ = response.write_to(&mut stream, &HttpVersion::Http11);
+                drain_before_close(&stream);
+            }
+            OverloadPolicy::Drop => println!("Worker queue full; dropping connection."),
+        }
+        None
+    }
+
+    fn handle_connection(&self, stream: net::TcpStream) {
+        let responder = self.responder();
+        let timeouts = self.timeouts();
+        let limits = self.request_limits;
+        let shutdown = self.shutdown.clone();
         self.worker_pool.execute(move || {
-            let request = match read_http_request(&mut stream) {
-                Ok(r) => r,
-                Err(err) => {
-                    println!("{}", err);
-                    stream.write_all(HttpResponse::new(StatusCode::BAD_REQUEST).build().as_bytes()).unwrap();
-                    return
+            if stream.set_write_timeout(Some(timeouts.write)).is_err() {
+                return
+            }
+            let mut reader = io::BufReader::new(TimedReader::new(&stream, timeouts.keep_alive));
+            let mut writer = &stream;
+            let connection = shutdown.track(&stream);
+            let mut is_first_request = true;
+
+            loop {
+                // The first request is still served, its client connected before the shutdown. The
+                // connection only counts as idle from the second request on, so the shutdown does
+                // not close it while the first one is on its way.
+                if !is_first_request && connection.set_idle() {
+                    break
                 }
-            };
+                is_first_request = false;
 
-            debug!(&request);
+                // idle keep-alive connections are closed once the wait for a request times out
+                let waiting = wait_for_request(&mut reader, &timeouts);
+                connection.set_busy();
+                if !waiting {
+                    break
+                }
 
+                let request = match read_timed_request(&mut reader, &timeouts, &limits) {
+                    Ok(r) => r,
+                    Err(ParseError::ConnectionClosed) => break,
+                    Err(err) => {
+                        println!("{}", err);
+                        responder.reject(&err, &mut writer);
+                        break
+                    }
+                };
 
-            let mut found_routes = routes_clone.iter().filter(|r| r.uri.is_match(&request.uri)).peekable();
-            if found_routes.peek().is_none() {
-                stream.write_all(HttpResponse::new(StatusCode::NOT_FOUND).build().as_bytes()).unwrap();
-                return;
+                if !responder.respond(request, &mut writer) {
+                    break
+                }
             }
+        });
+    }
+
+    // The answers of the router itself, e.g. to an OPTIONS preflight, pass the global middlewares
+    // as well, so a CORS middleware can add its headers to them
+    fn route_request(routes: &Router<RegexRoute>, middlewares: &[Arc<dyn Middleware>], mut request: HttpRequest) -> HttpResponse {
+        match find_route(routes, &mut request, |r| &r.method) {
+            Ok(route) => Next::new(&route.middlewares, route.handler.as_ref().as_ref()).run(request),
+            Err(response) => Next::new(middlewares, &Prepared(Mutex::new(Some(response)))).run(request),
+        }
+    }
+}
 
-            let found_route = found_routes.find(|r| r.method == request.method);
+// Ends a middleware chain in a response that is already made. A chain calls its handler once.
+struct Prepared(Mutex<Option<HttpResponse>>);
 
-            if let Some(route) = found_route {
-                let response = route.handler.respond(request);
-                stream.write_all(&response.build().as_bytes()).unwrap()
-            } else {
-                stream.write_all(HttpResponse::new(StatusCode::METHOD_NOT_ALLOWED).build().as_bytes()).unwrap();
-            }
+impl RouteHandler for Prepared {
+    fn respond(&self, _: HttpRequest) -> HttpResponse {
+        let response = self.0.lock().unwrap().take();
+        response.unwrap_or_else(|| HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR))
+    }
+}
+
+// Closing a socket with unread request bytes resets the connection, and the client may never see
+// the response written before. The request is read for a moment after the write side is shut. A
+// stream from the reactor does not block, only what has arrived already is read there.
+fn drain_before_close(stream: &net::TcpStream) {
+    use std::io::Read;
+    if stream.shutdown(net::Shutdown::Write).is_err() {
+        return
+    }
+    let deadline = Instant::now() + SHED_DRAIN_TIMEOUT;
+    let mut buf = [0; 4096];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || stream.set_read_timeout(Some(remaining)).is_err() {
+            return
+        }
+        match (&*stream).read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(_) => (),
+        }
+    }
+}
+
+// Finds the route for the request and fills in its path parameters. The error is the response
+// when no route fits: 404 for an unknown path, 405 for a method the path has no route for. An
+// OPTIONS request is answered for every known path unless a route handles it. Both list the
+// allowed methods in the Allow header.
+pub(super) fn find_route<'r, T, M>(routes: &'r Router<T>, request: &mut HttpRequest, method: M) -> Result<&'r T, HttpResponse>
+where M: Fn(&T) -> &HttpMethod {
+    let Some(found) = routes.lookup(&request.uri) else {
+        return Err(HttpResponse::new(StatusCode::NOT_FOUND));
+    };
+    if let Some((route, path_params)) = found.select(|r| *method(r) == request.method) {
+        request.path_params = path_params;
+        return Ok(route);
+    }
+
+    let mut allowed: Vec<String> = Vec::new();
+    for allow in found.routes().map(|r| method(r).to_string()).chain([HttpMethod::OPTIONS.to_string()]) {
+        if !allowed.contains(&allow) {
+            allowed.push(allow);
+        }
+    }
+    let status_code = match request.method {
+        HttpMethod::OPTIONS => StatusCode::OK,
+        _ => StatusCode::METHOD_NOT_ALLOWED,
+    };
+    let mut response = HttpResponse::new(status_code);
+    response.add_header(String::from("Allow"), allowed.join(", "));
+    Err(response)
+}
+
+// Sets the Connection header of the response and returns whether the connection stays open
+pub(super) fn set_connection(response: &mut HttpResponse, version: HttpVersion, mut keep_alive: bool, shutting_down: bool) -> bool {
+    // an HTTP/1.0 client only sees the end of a body of unknown length when we close
+    if version == HttpVersion::Http10 && response.body.len().is_none() {
+        keep_alive = false;
+    }
+    if shutting_down {
+        keep_alive = false;
+    }
+    if !keep_alive {
+        response.add_header(String::from("Connection"), String::from("close"));
+    } else if version == HttpVersion::Http10 {
+        response.add_header(String::from("Connection"), String::from("keep-alive"));
+    }
+    keep_alive
+}
+
+// Everything a worker needs to answer requests, cloned into the jobs of both connection modes
+#[derive(Clone)]
+pub(super) struct Responder {
+    routes: Arc<Router<RegexRoute>>,
+    middlewares: Arc<Vec<Arc<dyn Middleware>>>,
+    state: Arc<AppState>,
+    shutdown: Arc<ShutdownState>,
+    pool_stats: Arc<PoolStats>,
+    error_renderer: Arc<dyn ErrorRenderer>,
+}
+
+impl Responder {
+    // Routes the request and writes the response. Returns whether the connection stays open for
+    // the next request.
+    pub fn respond<W: Write>(&self, mut request: HttpRequest, writer: &mut W) -> bool {
+        debug!(&request);
+        request.state = self.state.clone();
+
+        let keep_alive = request.keep_alive();
+        let version = request.version;
+        // a panicking handler is answered with a 500 instead of taking down the connection
+        let response = panic::catch_unwind(AssertUnwindSafe(|| HttpServer::route_request(&self.routes, &self.middlewares, request)));
+        let mut response = response.unwrap_or_else(|_| {
+            self.pool_stats.record_panic();
+            HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
         });
+        let keep_alive = set_connection(&mut response, version, keep_alive, self.shutdown.is_requested());
+
+        response.write_to(writer, &version).is_ok() && keep_alive
+    }
+
+    // Answers a request that could not be read with the rendered error, the connection is closed
+    // afterwards. Errors that leave nobody to answer are not answered.
+    pub fn reject<W: Write>(&self, error: &ParseError, writer: &mut W) {
+        if let Some(status_code) = error.status_code() {
+            let mut response = self.error_renderer.render(error, status_code);
+            response.add_header(String::from("Connection"), String::from("close"));
+            let _ = response.write_to(writer, &HttpVersion::Http11);
+        }
+    }
+}
+
+#[allow(dead_code)]
+fn get(address: net::SocketAddr, uri: &str) -> String {
+    use std::io::Read;
+    let mut stream = net::TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", uri).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn serves_every_listener() {
+    use super::server_builder::HttpServerBuilder;
+    use super::service::Route;
+    let server = HttpServerBuilder::new()
+        .bind("127.0.0.1:0")
+        .bind("127.0.0.1:0")
+        .worker_pool_limit(2)
+        .add_route(Route::new("/", HttpMethod::GET, |_| StatusCode::OK))
+        .build()
+        .unwrap();
+    let addresses: Vec<net::SocketAddr> = server.listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+    assert_eq!(addresses.len(), 2);
+    let handle = server.handle();
+    let running = thread::spawn(move || server.run());
+
+    for address in addresses {
+        assert!(get(address, "/").starts_with("HTTP/1.1 200 OK"));
+    }
+    handle.shutdown();
+    running.join().unwrap();
+}
+
+#[test]
+fn shutdown_waits_for_server_that_has_not_run_yet() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use super::server_builder::HttpServerBuilder;
+    let server = HttpServerBuilder::new().bind("127.0.0.1:0").worker_pool_limit(1).build().unwrap();
+    let handle = server.handle();
+    let started = Arc::new(AtomicBool::new(false));
+    let running = thread::spawn({
+        let started = started.clone();
+        move || {
+            thread::sleep(Duration::from_millis(100));
+            started.store(true, Ordering::SeqCst);
+            server.run();
+        }
+    });
+    // the server stops right away once it runs after all
+    handle.shutdown();
+    assert!(started.load(Ordering::SeqCst));
+    running.join().unwrap();
+
+    let server = HttpServerBuilder::new().bind("127.0.0.1:0").worker_pool_limit(1).build().unwrap();
+    let handle = server.handle();
+    drop(server);
+    handle.shutdown();
+}
+
+#[test]
+fn drains_in_flight_requests_and_closes_idle_connections() {
+    use std::io::Read;
+    use super::server_builder::HttpServerBuilder;
+    use super::service::Route;
+    let server = HttpServerBuilder::new()
+        .bind("127.0.0.1:0")
+        .worker_pool_limit(4)
+        .add_route(Route::new("/", HttpMethod::GET, |_| StatusCode::OK))
+        .add_route(Route::new("/slow", HttpMethod::GET, |_| {
+            thread::sleep(Duration::from_millis(300));
+            StatusCode::OK
+        }))
+        .build()
+        .unwrap();
+    let address = server.listeners[0].local_addr().unwrap();
+    let handle = server.handle();
+    let running = thread::spawn(move || server.run());
+
+    // a keep-alive connection idle after its first response
+    let mut idle = net::TcpStream::connect(address).unwrap();
+    idle.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    write!(idle, "GET / HTTP/1.1\r\n\r\n").unwrap();
+    let mut response = [0; 1024];
+    assert!(idle.read(&mut response).unwrap() > 0);
+
+    // a connection whose first request only arrives once the shutdown started
+    let mut fresh = net::TcpStream::connect(address).unwrap();
+    fresh.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let in_flight = thread::spawn(move || get(address, "/slow"));
+    thread::sleep(Duration::from_millis(100));
+
+    let shutdown = thread::spawn(move || handle.shutdown());
+    thread::sleep(Duration::from_millis(100));
+    write!(fresh, "GET / HTTP/1.1\r\n\r\n").unwrap();
+    let mut first = String::new();
+    fresh.read_to_string(&mut first).unwrap();
+    assert!(first.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(first.contains("Connection: close\r\n"));
+    assert_eq!(idle.read(&mut response).unwrap(), 0);
+
+    shutdown.join().unwrap();
+    assert!(in_flight.join().unwrap().starts_with("HTTP/1.1 200 OK\r\n"));
+    running.join().unwrap();
+}
+
+#[test]
+fn sheds_load_when_queue_is_full() {
+    use std::io::Read;
+    use super::server_builder::HttpServerBuilder;
+    use super::service::Route;
+    let policies = [
+        (ConnectionMode::Threaded, OverloadPolicy::Reject { retry_after: Duration::from_secs(5) }),
+        (ConnectionMode::EventDriven, OverloadPolicy::Reject { retry_after: Duration::from_secs(5) }),
+        (ConnectionMode::Threaded, OverloadPolicy::Drop),
+    ];
+    for (mode, policy) in policies {
+        let server = HttpServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .connection_mode(mode)
+            .worker_pool_limit(1)
+            .min_workers(1)
+            .queue_capacity(1)
+            .overload_policy(policy)
+            .add_route(Route::new("/slow", HttpMethod::GET, |_| {
+                thread::sleep(Duration::from_millis(400));
+                StatusCode::OK
+            }))
+            .build()
+            .unwrap();
+        let address = server.listeners[0].local_addr().unwrap();
+        let handle = server.handle();
+        let running = thread::spawn(move || server.run());
+
+        // one request keeps the worker busy, the next one fills the queue
+        let busy = thread::spawn(move || get(address, "/slow"));
+        thread::sleep(Duration::from_millis(100));
+        let queued = thread::spawn(move || get(address, "/slow"));
+        thread::sleep(Duration::from_millis(100));
+
+        let mut shed = net::TcpStream::connect(address).unwrap();
+        shed.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write!(shed, "GET /slow HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = Vec::new();
+        let _ = shed.read_to_end(&mut response);
+        let response = String::from_utf8_lossy(&response);
+        match policy {
+            OverloadPolicy::Reject { .. } => {
+                assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{:?}", mode);
+                assert!(response.contains("Retry-After: 5\r\n"));
+            }
+            _ => assert_eq!(response, ""),
+        }
+
+        assert!(busy.join().unwrap().starts_with("HTTP/1.1 200 OK"));
+        assert!(queued.join().unwrap().starts_with("HTTP/1.1 200 OK"));
+        handle.shutdown();
+        running.join().unwrap();
     }
 }
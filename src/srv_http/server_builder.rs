@@ -1,24 +1,88 @@
 use super::{
-    server::{HttpServer, RouteAddress, RegexRoute}, service::Route, workpool::WorkerPool,
+    middleware::Middleware, request::RequestLimits, router::{RouteAddress, Router}, server::{HttpServer, RegexRoute},
+    server_handle::{ShutdownState, StopGuard},
+    service::{route_conflicts, DefaultErrorRenderer, ErrorRenderer, HttpService, Route, ServerError},
+    state::AppState, workpool::WorkerPool,
 };
-use std::{net, sync::Arc};
+use std::{net, sync::Arc, time::Duration};
+
+// What the acceptor does with a new connection while the worker queue is full
+#[derive(Debug, Clone, Copy)]
+pub enum OverloadPolicy {
+    Block,
+    Reject { retry_after: Duration },
+    Drop,
+}
+
+// How connections are served. Threaded gives every connection a worker for its whole lifetime.
+// EventDriven lets a reactor thread own the sockets and only hands complete requests to the
+// workers, so slow clients and idle keep-alive connections do not hold a worker.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionMode {
+    Threaded,
+    EventDriven,
+}
 
 pub struct HttpServerBuilder {
     bindings: Vec<net::SocketAddr>,
-    routes: Vec<RegexRoute>,
+    routes: Vec<Route<'static>>,
+    middlewares: Vec<Arc<dyn Middleware>>,
     worker_pool_limit: usize,
+    min_workers: usize,
+    worker_keep_alive: Duration,
+    queue_capacity: usize,
+    overload_policy: OverloadPolicy,
+    connection_mode: ConnectionMode,
+    keep_alive_timeout: Duration,
+    header_read_timeout: Duration,
+    request_head_timeout: Duration,
+    body_read_timeout: Duration,
+    write_timeout: Duration,
+    request_limits: RequestLimits,
+    error_renderer: Arc<dyn ErrorRenderer>,
+    state: AppState,
+    shutdown_timeout: Duration,
 }
 
-// add_middleware
-
 const WORKER_POOL_LIMIT_DEFAULT: usize = 16;
+const MIN_WORKERS_DEFAULT: usize = 2;
+const WORKER_KEEP_ALIVE_DEFAULT: Duration = Duration::from_secs(60);
+const QUEUE_CAPACITY_DEFAULT: usize = 1024;
+// the connection timeouts are shared with the AsyncHttpServerBuilder
+pub(super) const KEEP_ALIVE_TIMEOUT_DEFAULT: Duration = Duration::from_secs(5);
+pub(super) const HEADER_READ_TIMEOUT_DEFAULT: Duration = Duration::from_secs(5);
+pub(super) const REQUEST_HEAD_TIMEOUT_DEFAULT: Duration = Duration::from_secs(10);
+pub(super) const BODY_READ_TIMEOUT_DEFAULT: Duration = Duration::from_secs(10);
+pub(super) const WRITE_TIMEOUT_DEFAULT: Duration = Duration::from_secs(10);
+pub(super) const SHUTDOWN_TIMEOUT_DEFAULT: Duration = Duration::from_secs(30);
+
+impl Default for HttpServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl HttpServerBuilder {
     pub fn new() -> Self {
         HttpServerBuilder {
             bindings: Vec::new(),
             routes: Vec::new(),
+            middlewares: Vec::new(),
             worker_pool_limit: WORKER_POOL_LIMIT_DEFAULT,
+            min_workers: MIN_WORKERS_DEFAULT,
+            worker_keep_alive: WORKER_KEEP_ALIVE_DEFAULT,
+            queue_capacity: QUEUE_CAPACITY_DEFAULT,
+            overload_policy: OverloadPolicy::Block,
+            connection_mode: ConnectionMode::Threaded,
+            keep_alive_timeout: KEEP_ALIVE_TIMEOUT_DEFAULT,
+            header_read_timeout: HEADER_READ_TIMEOUT_DEFAULT,
+            request_head_timeout: REQUEST_HEAD_TIMEOUT_DEFAULT,
+            body_read_timeout: BODY_READ_TIMEOUT_DEFAULT,
+            write_timeout: WRITE_TIMEOUT_DEFAULT,
+            request_limits: RequestLimits::default(),
+            error_renderer: Arc::new(DefaultErrorRenderer::new()),
+            state: AppState::default(),
+            shutdown_timeout: SHUTDOWN_TIMEOUT_DEFAULT,
         }
     }
 
@@ -28,38 +92,285 @@ impl HttpServerBuilder {
         self
     }
 
+    // The pool starts with min_workers threads and grows up to worker_pool_limit while connections
+    // wait in the queue. Set it to worker_pool_limit for a fixed size pool.
+    pub fn min_workers(mut self, size: usize) -> Self {
+        self.min_workers = size;
+        self
+    }
+
+    // how long a worker above min_workers may stay idle before it exits
+    pub fn worker_keep_alive(mut self, keep_alive: Duration) -> Self {
+        assert!(!keep_alive.is_zero());
+        self.worker_keep_alive = keep_alive;
+        self
+    }
+
+    // the number of accepted connections that may wait for a free worker
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0);
+        self.queue_capacity = capacity;
+        self
+    }
+
+    pub fn overload_policy(mut self, policy: OverloadPolicy) -> Self {
+        self.overload_policy = policy;
+        self
+    }
+
+    pub fn connection_mode(mut self, mode: ConnectionMode) -> Self {
+        self.connection_mode = mode;
+        self
+    }
+
+    pub fn keep_alive_timeout(mut self, timeout: Duration) -> Self {
+        assert!(!timeout.is_zero());
+        self.keep_alive_timeout = timeout;
+        self
+    }
+
+    // how long the request line and headers may stall between two reads
+    pub fn header_read_timeout(mut self, timeout: Duration) -> Self {
+        assert!(!timeout.is_zero());
+        self.header_read_timeout = timeout;
+        self
+    }
+
+    // The time from the first byte of a request until its headers are complete. Bounds clients
+    // that keep a connection busy by trickling in the head a byte at a time.
+    pub fn request_head_timeout(mut self, timeout: Duration) -> Self {
+        assert!(!timeout.is_zero());
+        self.request_head_timeout = timeout;
+        self
+    }
+
+    // how long the body may stall between two reads
+    pub fn body_read_timeout(mut self, timeout: Duration) -> Self {
+        assert!(!timeout.is_zero());
+        self.body_read_timeout = timeout;
+        self
+    }
+
+    // how long writing the response may stall before the connection is closed
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        assert!(!timeout.is_zero());
+        self.write_timeout = timeout;
+        self
+    }
+
+    // longer request lines are answered with 414 URI Too Long
+    pub fn max_request_line(mut self, bytes: usize) -> Self {
+        self.request_limits.max_request_line = bytes;
+        self
+    }
+
+    // more headers are answered with 431 Request Header Fields Too Large
+    pub fn max_headers(mut self, count: usize) -> Self {
+        self.request_limits.max_headers = count;
+        self
+    }
+
+    // the size of all header lines together, more is answered with 431
+    pub fn max_header_bytes(mut self, bytes: usize) -> Self {
+        self.request_limits.max_header_bytes = bytes;
+        self
+    }
+
+    // larger bodies are answered with 413 Payload Too Large before they are read
+    pub fn max_body_size(mut self, bytes: usize) -> Self {
+        self.request_limits.max_body_size = bytes;
+        self
+    }
+
+    // Renders the responses to requests that could not be read, e.g. 400 Bad Request or 501 Not
+    // Implemented. Use DefaultErrorRenderer::with_diagnostics() to explain the error in the body.
+    pub fn error_renderer<R>(mut self, renderer: R) -> Self
+    where R: ErrorRenderer + 'static {
+        self.error_renderer = Arc::new(renderer);
+        self
+    }
+
+    // how long a shutdown triggered by SIGINT or SIGTERM waits for in-flight requests
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    // Handlers get the state with request.state::<S>() or the State<S> extractor. Registering a
+    // second value of the same type replaces the first.
+    pub fn with_state<S: Send + Sync + 'static>(mut self, state: S) -> Self {
+        self.state.insert(state);
+        self
+    }
+
     // todo add error handling
     pub fn bind<A: net::ToSocketAddrs>(mut self, address: A) -> Self {
         let mut binding = address
             .to_socket_addrs()
             .expect("Unable to resolve domain");
-        match binding.next() {
-            Some(b) => self.bindings.push(b),
-            None => (),
+        if let Some(b) = binding.next() {
+            self.bindings.push(b);
         }
         self
     }
 
     pub fn add_route(mut self, route: Route<'static>) -> Self {
-        let regex_route = RegexRoute {
-            uri: RouteAddress::new(route.uri),
-            method: route.method,
-            handler: route.handler,
-        };
-        self.routes.push(regex_route);
+        self.routes.push(route);
+        self
+    }
+
+    // Registers the routes of the service under the prefix, e.g. its /user/{id} becomes
+    // /api/v1/user/{id} when mounted under /api/v1 and its / becomes /api/v1
+    pub fn mount(mut self, prefix: &str, service: HttpService<'static>) -> Self {
+        assert!(prefix.starts_with('/'), "A mount prefix must start with a slash: {}", prefix);
+        self.routes.extend(service.into_routes(prefix));
+        self
+    }
+
+    // Middlewares run in the order they are registered, the first one registered is the outermost
+    // and sees the request first and the response last
+    pub fn add_middleware<M>(mut self, middleware: M) -> Self
+    where M: Middleware + 'static {
+        self.middlewares.push(Arc::new(middleware));
         self
     }
 
-    pub fn build(self) -> HttpServer {
+    // Fails when two routes answer the same requests, i.e. the same method with templates that
+    // only differ in their parameter names. The route registered later would never be reached.
+    // Fails as well on a template that cannot be matched, see RouteAddress, and on an address that
+    // cannot be bound.
+    pub fn build(self) -> Result<HttpServer, ServerError> {
+        let conflicts = route_conflicts(self.routes.iter().map(|r| (&r.method, r.uri.as_str())));
+        if !conflicts.is_empty() {
+            return Err(ServerError::RouteConflict(conflicts));
+        }
+
+        let routes = self.routes
+            .into_iter()
+            .map(|route| Ok(RegexRoute {
+                uri: RouteAddress::new(route.uri)?,
+                method: route.method,
+                handler: route.handler,
+                middlewares: self.middlewares.iter().cloned().chain(route.middlewares).collect(),
+            }))
+            .collect::<Result<Vec<RegexRoute>, ServerError>>()?;
+
         let mut listeners: Vec<net::TcpListener> = Vec::new();
         for binding in &self.bindings {
-            listeners.push(net::TcpListener::bind(binding).unwrap());
+            listeners.push(net::TcpListener::bind(binding).map_err(ServerError::Bind)?);
         }
 
-        HttpServer {
+        let addresses = listeners.iter().filter_map(|l| l.local_addr().ok()).collect();
+
+        let shutdown = Arc::new(ShutdownState::new(addresses));
+        Ok(HttpServer {
+            _stop_guard: StopGuard(shutdown.clone()),
+            shutdown,
+            shutdown_timeout: self.shutdown_timeout,
             listeners,
-            routes: Arc::new(self.routes),
-            worker_pool: WorkerPool::new(self.worker_pool_limit),
-        }
+            routes: Arc::new(Router::new(routes, |r| &r.uri)),
+            middlewares: Arc::new(self.middlewares),
+            worker_pool: WorkerPool::elastic(
+                self.min_workers.min(self.worker_pool_limit),
+                self.worker_pool_limit,
+                self.worker_keep_alive,
+                self.queue_capacity,
+            ),
+            overload_policy: self.overload_policy,
+            connection_mode: self.connection_mode,
+            keep_alive_timeout: self.keep_alive_timeout,
+            header_read_timeout: self.header_read_timeout,
+            request_head_timeout: self.request_head_timeout,
+            body_read_timeout: self.body_read_timeout,
+            write_timeout: self.write_timeout,
+            request_limits: self.request_limits,
+            error_renderer: self.error_renderer,
+            state: Arc::new(self.state),
+        })
     }
 }
+
+#[test]
+fn rejects_conflicting_routes() {
+    use super::http_constants::HttpMethod;
+    let result = HttpServerBuilder::new()
+        .add_route(Route::new("/user/{id}", HttpMethod::GET, |_| http::StatusCode::OK))
+        .add_route(Route::new("/user/{id}", HttpMethod::POST, |_| http::StatusCode::OK))
+        .add_route(Route::new("/user/new", HttpMethod::GET, |_| http::StatusCode::OK))
+        .add_route(Route::new("/user/{name}", HttpMethod::GET, |_| http::StatusCode::OK))
+        .build();
+    match result {
+        Err(err) => assert_eq!(err.to_string(), "Conflicting Routes: GET /user/{id} conflicts with GET /user/{name}"),
+        Ok(_) => panic!("expected conflicting routes"),
+    }
+}
+
+#[test]
+fn reports_address_in_use() {
+    let taken = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let result = HttpServerBuilder::new().bind(taken.local_addr().unwrap()).build();
+    assert!(matches!(result, Err(ServerError::Bind(err)) if err.kind() == std::io::ErrorKind::AddrInUse));
+}
+
+#[test]
+fn rejects_invalid_routes() {
+    use super::http_constants::HttpMethod;
+    let result = HttpServerBuilder::new()
+        .add_route(Route::new("/user/{id:[0-9}", HttpMethod::GET, |_| http::StatusCode::OK))
+        .build();
+    assert!(matches!(result, Err(ServerError::InvalidRoute(template, _)) if template == "/user/{id:[0-9}"));
+}
+
+#[cfg(test)]
+use super::middleware::TagMiddleware;
+
+#[allow(dead_code)]
+fn respond(server: &HttpServer, request: &str) -> String {
+    let request = super::request::read_http_request(&mut request.as_bytes()).unwrap();
+    let mut out: Vec<u8> = Vec::new();
+    server.responder().respond(request, &mut out);
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn mounts_service_with_group_middlewares() {
+    use super::http_constants::HttpMethod;
+    let mut service = HttpService::new();
+    service.add_middleware(TagMiddleware("group"));
+    service.add_route(Route::new("/", HttpMethod::GET, |_| http::StatusCode::OK));
+    service.add_route(Route::new("/user/{id}", HttpMethod::GET, |_| http::StatusCode::OK));
+    let server = HttpServerBuilder::new()
+        .worker_pool_limit(1)
+        .add_middleware(TagMiddleware("global"))
+        .mount("/api/v1/", service)
+        .add_route(Route::new("/health", HttpMethod::GET, |_| http::StatusCode::OK))
+        .build()
+        .unwrap();
+
+    let mounted = respond(&server, "GET /api/v1/user/7 HTTP/1.1\r\n\r\n");
+    assert!(mounted.starts_with("HTTP/1.1 200 OK\r\nX-Tag: group\r\nX-Tag: global\r\n"));
+    assert!(respond(&server, "GET /api/v1 HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200 OK"));
+    assert!(respond(&server, "GET /user/7 HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404"));
+    let outside = respond(&server, "GET /health HTTP/1.1\r\n\r\n");
+    assert!(outside.contains("X-Tag: global") && !outside.contains("X-Tag: group"));
+}
+
+#[test]
+fn lists_allowed_methods() {
+    use super::http_constants::HttpMethod;
+    let server = HttpServerBuilder::new()
+        .worker_pool_limit(1)
+        .add_route(Route::new("/user/{id}", HttpMethod::GET, |_| http::StatusCode::OK))
+        .add_route(Route::new("/user/{id}", HttpMethod::DELETE, |_| http::StatusCode::OK))
+        .add_route(Route::new("/custom", HttpMethod::OPTIONS, |_| http::StatusCode::NO_CONTENT))
+        .add_middleware(TagMiddleware("global"))
+        .build()
+        .unwrap();
+
+    let not_allowed = respond(&server, "POST /user/7 HTTP/1.1\r\n\r\n");
+    assert!(not_allowed.starts_with("HTTP/1.1 405 Method Not Allowed\r\nAllow: GET, DELETE, OPTIONS\r\nX-Tag: global\r\n"));
+    let options = respond(&server, "OPTIONS /user/7 HTTP/1.1\r\n\r\n");
+    assert!(options.starts_with("HTTP/1.1 200 OK\r\nAllow: GET, DELETE, OPTIONS\r\nX-Tag: global\r\n"));
+    assert!(respond(&server, "OPTIONS /custom HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 204"));
+    assert!(respond(&server, "OPTIONS /unknown HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404"));
+}
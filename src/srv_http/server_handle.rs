@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::net;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+use super::workpool::PoolStats;
+
+// Shutdown bookkeeping shared by the acceptors, the connections and every ServerHandle
+pub(crate) struct ShutdownState {
+    requested: AtomicBool,
+    timeout: Mutex<Option<Duration>>,
+    addresses: Vec<net::SocketAddr>,
+    connections: Mutex<HashMap<usize, (net::TcpStream, Arc<AtomicBool>)>>,
+    next_connection_id: AtomicUsize,
+    stopped: Mutex<bool>,
+    stopped_signal: Condvar,
+}
+
+// Held by the HttpServer. The server counts as stopped once it is dropped, i.e. when run returns
+// or when it is dropped without ever running.
+pub(crate) struct StopGuard(pub Arc<ShutdownState>);
+
+impl Drop for StopGuard {
+    fn drop(&mut self) {
+        self.0.set_stopped();
+    }
+}
+
+// A connection registered for the shutdown as long as it is open. Marking it idle only flips a
+// flag, so the registry is not locked for every request.
+pub(crate) struct TrackedConnection<'a> {
+    state: &'a ShutdownState,
+    id: Option<usize>,
+    idle: Arc<AtomicBool>,
+}
+
+impl TrackedConnection<'_> {
+    // An idle connection waits for its next request and gets closed on shutdown. Returns whether
+    // the shutdown was requested already, the connection should then close itself.
+    pub fn set_idle(&self) -> bool {
+        self.idle.store(true, Ordering::SeqCst);
+        self.state.is_requested()
+    }
+
+    pub fn set_busy(&self) {
+        self.idle.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Drop for TrackedConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.state.connections.lock().unwrap().remove(&id);
+        }
+    }
+}
+
+impl ShutdownState {
+    pub fn new(addresses: Vec<net::SocketAddr>) -> Self {
+        ShutdownState {
+            requested: AtomicBool::new(false),
+            timeout: Mutex::new(None),
+            addresses,
+            connections: Mutex::new(HashMap::new()),
+            next_connection_id: AtomicUsize::new(0),
+            stopped: Mutex::new(false),
+            stopped_signal: Condvar::new(),
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        *self.timeout.lock().unwrap()
+    }
+
+    fn request(&self, timeout: Option<Duration>) {
+        *self.timeout.lock().unwrap() = timeout;
+        if self.requested.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        // acceptors are blocked in accept, a connection wakes them up to see the shutdown
+        for address in &self.addresses {
+            let mut address = *address;
+            if address.ip().is_unspecified() {
+                match address {
+                    net::SocketAddr::V4(_) => address.set_ip(net::Ipv4Addr::LOCALHOST.into()),
+                    net::SocketAddr::V6(_) => address.set_ip(net::Ipv6Addr::LOCALHOST.into()),
+                }
+            }
+            let _ = net::TcpStream::connect_timeout(&address, Duration::from_secs(1));
+        }
+
+        for (stream, idle) in self.connections.lock().unwrap().values() {
+            if idle.load(Ordering::SeqCst) {
+                let _ = stream.shutdown(net::Shutdown::Both);
+            }
+        }
+    }
+
+    // Registers a connection once when it is opened. A connection that cannot be registered
+    // relies on its read timeout to close on shutdown.
+    pub fn track(&self, stream: &net::TcpStream) -> TrackedConnection<'_> {
+        let idle = Arc::new(AtomicBool::new(false));
+        let id = stream.try_clone().ok().map(|stream| {
+            let id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+            self.connections.lock().unwrap().insert(id, (stream, idle.clone()));
+            id
+        });
+        TrackedConnection {
+            state: self,
+            id,
+            idle,
+        }
+    }
+
+    pub fn set_stopped(&self) {
+        *self.stopped.lock().unwrap() = true;
+        self.stopped_signal.notify_all();
+    }
+
+    // a server that has not started yet is waited for as well, it stops as soon as it runs
+    fn wait_stopped(&self) {
+        let mut stopped = self.stopped.lock().unwrap();
+        while !*stopped {
+            stopped = self.stopped_signal.wait(stopped).unwrap();
+        }
+    }
+}
+
+// A handle to stop a running HttpServer from another thread. Shutting down stops accepting new
+// connections, closes idle keep-alive connections and waits for in-flight requests to finish.
+// Calling shutdown from inside a route handler waits on the handler itself, use
+// shutdown_timeout there. Shutting down a server that has not run yet waits until it has run and
+// stopped, which it does as soon as it started, or until it is dropped.
+#[derive(Clone)]
+pub struct ServerHandle {
+    state: Arc<ShutdownState>,
+    pool_stats: Arc<PoolStats>,
+    signal_timeout: Duration,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(state: Arc<ShutdownState>, pool_stats: Arc<PoolStats>, signal_timeout: Duration) -> Self {
+        ServerHandle {
+            state,
+            pool_stats,
+            signal_timeout,
+        }
+    }
+
+    // connections accepted but not yet picked up by a worker
+    pub fn queue_depth(&self) -> usize {
+        self.pool_stats.queue_depth()
+    }
+
+    // worker threads currently alive in the elastic pool
+    pub fn worker_count(&self) -> usize {
+        self.pool_stats.worker_count()
+    }
+
+    // panics caught in route handlers and worker threads since the server started
+    pub fn panic_count(&self) -> usize {
+        self.pool_stats.panic_count()
+    }
+
+    pub fn shutdown(&self) {
+        self.state.request(None);
+        self.state.wait_stopped();
+    }
+
+    // in-flight requests still running after the timeout are abandoned
+    pub fn shutdown_timeout(&self, timeout: Duration) {
+        self.state.request(Some(timeout));
+        self.state.wait_stopped();
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.state.is_requested()
+    }
+
+    // Shuts the server down with the timeout set on the HttpServerBuilder on SIGINT or SIGTERM
+    pub fn shutdown_on_signals(&self) {
+        let mut signals = Signals::new([SIGINT, SIGTERM]).expect("Unable to register signal handlers");
+        let handle = self.clone();
+        thread::Builder::new()
+            .name(String::from("signal handler"))
+            .spawn(move || {
+                if let Some(signal) = signals.forever().next() {
+                    println!("Received signal {}; shutting down.", signal);
+                    handle.shutdown_timeout(handle.signal_timeout);
+                }
+            })
+            .expect("Unable to spawn signal handler thread");
+    }
+}
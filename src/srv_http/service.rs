@@ -1,23 +1,109 @@
+use std::fmt;
+use std::io;
 use std::sync::Arc;
 
+use http::StatusCode;
+
 use super::{
-    request::HttpRequest,
-    response::HttpResponse, http_constants::HttpMethod
+    request::{HttpRequest, ParseError},
+    response::{HttpResponse, IntoResponse}, http_constants::HttpMethod, middleware::Middleware,
+    router::same_paths,
 };
 
 pub trait RouteHandler: Sync + Send {
     fn respond(&self, request: HttpRequest) -> HttpResponse;
 }
 
+// Closures and functions can be registered directly, returning an HttpResponse or a Result whose
+// error converts into a response. A closure that calls methods on the request needs its
+// parameter annotated, e.g. |request: HttpRequest| ...
+impl<F, R> RouteHandler for F
+where F: Fn(HttpRequest) -> R + Sync + Send, R: IntoResponse {
+    fn respond(&self, request: HttpRequest) -> HttpResponse {
+        self(request).into_response()
+    }
+}
+
+// Renders the response to a request that could not be read, e.g. to answer with a JSON error
+// body. The status is the one the error maps to, the connection is closed after the response.
+pub trait ErrorRenderer: Sync + Send {
+    fn render(&self, error: &ParseError, status_code: StatusCode) -> HttpResponse;
+}
+
+impl<F> ErrorRenderer for F
+where F: Fn(&ParseError, StatusCode) -> HttpResponse + Sync + Send {
+    fn render(&self, error: &ParseError, status_code: StatusCode) -> HttpResponse {
+        self(error, status_code)
+    }
+}
+
+// Answers with the bare status, or with the error message as a plain text body when diagnostics
+// are enabled
+#[derive(Default)]
+pub struct DefaultErrorRenderer {
+    diagnostics: bool,
+}
+
+impl DefaultErrorRenderer {
+    pub fn new() -> Self {
+        DefaultErrorRenderer {
+            diagnostics: false,
+        }
+    }
+
+    pub fn with_diagnostics() -> Self {
+        DefaultErrorRenderer {
+            diagnostics: true,
+        }
+    }
+}
+
+impl ErrorRenderer for DefaultErrorRenderer {
+    fn render(&self, error: &ParseError, status_code: StatusCode) -> HttpResponse {
+        let response = HttpResponse::new(status_code);
+        if self.diagnostics {
+            response.text(error.to_string())
+        } else {
+            response
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum ServerError {
     Fail,
+    // pairs of routes answering the same requests, e.g. "GET /user/{id}" and "GET /user/{name}"
+    RouteConflict(Vec<(String, String)>),
+    // a route template that cannot be matched and why, e.g. "/user/{id:[0-9}" with its invalid regex
+    InvalidRoute(String, String),
+    // an address could not be bound, e.g. because the port is in use
+    Bind(io::Error),
+}
+
+impl std::error::Error for ServerError {}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self {
+            ServerError::Fail => write!(f, "Server Failure"),
+            ServerError::RouteConflict(conflicts) => {
+                let conflicts: Vec<String> = conflicts
+                    .iter()
+                    .map(|c| concat_string!(c.0, " conflicts with ", c.1))
+                    .collect();
+                write!(f, "Conflicting Routes: {}", conflicts.join(", "))
+            }
+            ServerError::InvalidRoute(template, reason) => write!(f, "Invalid Route {}: {}", template, reason),
+            ServerError::Bind(err) => write!(f, "Unable to bind: {}", err),
+        }
+    }
 }
 
 pub struct Route<'a> {
     pub uri: String,
     pub method: HttpMethod,
     pub handler: Arc<Box<dyn RouteHandler + 'a>>,
+    pub middlewares: Vec<Arc<dyn Middleware + 'a>>,
 }
 
 impl<'a> Route<'a> {
@@ -27,32 +113,106 @@ impl<'a> Route<'a> {
             uri: String::from(uri),
             method,
             handler: Arc::new(Box::new(handler)),
+            middlewares: Vec::new(),
         }
     }
+
+    // route middlewares run after the global middlewares registered on the HttpServerBuilder
+    pub fn with_middleware<M>(mut self, middleware: M) -> Self
+    where M: Middleware + 'a {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
 }
 
+// Routes are equal when they answer the same requests, parameter names do not tell them apart
 impl<'a> PartialEq for Route<'a> {
     fn eq(&self, other: &Self) -> bool {
-        self.uri == other.uri && self.method == other.method
+        self.method == other.method && same_paths(&self.uri, &other.uri)
     }
+}
 
-    fn ne(&self, other: &Self) -> bool {
-        !self.eq(other)
+// Every pair of routes answering the same requests, given by method and template. The route
+// registered first is named first. The async server checks its routes with this as well.
+pub(crate) fn route_conflicts<'r, I>(routes: I) -> Vec<(String, String)>
+where I: IntoIterator<Item = (&'r HttpMethod, &'r str)> {
+    let routes: Vec<(&HttpMethod, &str)> = routes.into_iter().collect();
+    let describe = |(method, uri): (&HttpMethod, &str)| concat_string!(method.to_string(), " ", uri);
+    let mut conflicts = Vec::new();
+    for (i, route) in routes.iter().enumerate() {
+        for other in routes[i + 1..].iter().filter(|r| r.0 == route.0 && same_paths(r.1, route.1)) {
+            conflicts.push((describe(*route), describe(*other)));
+        }
     }
+    conflicts
 }
 
+// A group of routes, e.g. the endpoints owned by one module, mounted on the server under a shared
+// prefix with HttpServerBuilder::mount. Its middlewares only run for its own routes.
+#[derive(Default)]
 pub struct HttpService<'a> {
     routes: Vec<Route<'a>>,
+    middlewares: Vec<Arc<dyn Middleware + 'a>>,
 }
 
 impl<'a> HttpService<'a> {
     pub fn new() -> Self {
         HttpService {
-            routes: Vec::new()
+            routes: Vec::new(),
+            middlewares: Vec::new(),
         }
     }
 
-    pub fn add_route<T>(&mut self, route: Route<'a>) -> () {
+    // the route template is relative to the prefix the service is mounted under
+    pub fn add_route(&mut self, route: Route<'a>) {
         self.routes.push(route);
     }
+
+    // group middlewares run after the global middlewares and before the route middlewares
+    pub fn add_middleware<M>(&mut self, middleware: M)
+    where M: Middleware + 'a {
+        self.middlewares.push(Arc::new(middleware));
+    }
+
+    // The routes with the prefix in front of their templates and the group middlewares in front
+    // of their own
+    pub(crate) fn into_routes(self, prefix: &str) -> Vec<Route<'a>> {
+        let prefix = prefix.trim_end_matches('/');
+        self.routes
+            .into_iter()
+            .map(|mut route| {
+                route.uri = match route.uri.as_str() {
+                    "/" if !prefix.is_empty() => String::from(prefix),
+                    uri => concat_string!(prefix, uri),
+                };
+                route.middlewares = self.middlewares.iter().cloned().chain(route.middlewares).collect();
+                route
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn responds_with_closure_result() {
+    let handler = |request: HttpRequest| -> Result<HttpResponse, super::request::ParamError> {
+        let id: u64 = request.param("id")?;
+        Ok(HttpResponse::new(http::StatusCode::OK).text(id.to_string()))
+    };
+    let mut request = super::request::read_http_request(&mut "GET / HTTP/1.1\r\n\r\n".as_bytes()).unwrap();
+    request.path_params.insert("id".to_string(), "x".to_string());
+    assert_eq!(handler.respond(request).status_code, http::StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn renders_parse_error_diagnostics() {
+    let status_code = http::StatusCode::NOT_IMPLEMENTED;
+    let response = DefaultErrorRenderer::new().render(&ParseError::Method, status_code);
+    assert_eq!(response.status_code, status_code);
+    assert_eq!(response.body.len(), Some(0));
+
+    let response = DefaultErrorRenderer::with_diagnostics().render(&ParseError::Method, status_code);
+    match response.body {
+        super::response::Body::Bytes(body) => assert_eq!(body, b"Unsupported Method"),
+        _ => panic!("expected a buffered body"),
+    }
 }
@@ -0,0 +1,32 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Shared application state registered with HttpServerBuilder::with_state, one value per type.
+// The values are kept behind an Arc so every request gets a cheap handle to the same value.
+#[derive(Debug, Default)]
+pub struct AppState {
+    values: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl AppState {
+    pub fn insert<S: Send + Sync + 'static>(&mut self, state: S) {
+        self.values.insert(TypeId::of::<S>(), Arc::new(state));
+    }
+
+    pub fn get<S: Send + Sync + 'static>(&self) -> Option<Arc<S>> {
+        self.values
+            .get(&TypeId::of::<S>())
+            .and_then(|s| s.clone().downcast::<S>().ok())
+    }
+}
+
+#[test]
+fn gets_state_by_type() {
+    let mut state = AppState::default();
+    state.insert(String::from("config"));
+    state.insert(42_u64);
+    assert_eq!(state.get::<String>().unwrap().as_str(), "config");
+    assert_eq!(*state.get::<u64>().unwrap(), 42);
+    assert!(state.get::<u32>().is_none());
+}
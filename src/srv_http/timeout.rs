@@ -0,0 +1,110 @@
+use std::io::{self, BufRead, BufReader, Read};
+use std::net;
+use std::time::{Duration, Instant};
+
+use super::request::{read_request_body, read_request_head, HttpRequest, ParseError, RequestLimits};
+
+// The timeouts a connection is held to, see the HttpServerBuilder methods of the same names
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Timeouts {
+    pub keep_alive: Duration,
+    pub header_read: Duration,
+    pub request_head: Duration,
+    pub body_read: Duration,
+    pub write: Duration,
+}
+
+// Reads a connection with a timeout per read and an optional deadline over all reads. The socket
+// timeout is set again before every read, so a client trickling in a byte at a time cannot
+// stretch the reads past the deadline.
+pub(crate) struct TimedReader<'a> {
+    stream: &'a net::TcpStream,
+    timeout: Duration,
+    deadline: Option<Instant>,
+}
+
+impl<'a> TimedReader<'a> {
+    pub fn new(stream: &'a net::TcpStream, timeout: Duration) -> Self {
+        TimedReader {
+            stream,
+            timeout,
+            deadline: None,
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration, deadline: Option<Instant>) {
+        self.timeout = timeout;
+        self.deadline = deadline;
+    }
+}
+
+impl Read for TimedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = match self.deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(io::Error::from(io::ErrorKind::TimedOut));
+                }
+                remaining.min(self.timeout)
+            }
+            None => self.timeout,
+        };
+        self.stream.set_read_timeout(Some(timeout))?;
+        self.stream.read(buf)
+    }
+}
+
+// Waits up to the keep-alive timeout for the first byte of the next request. Returns false when
+// the connection was closed or stayed idle for too long.
+pub(crate) fn wait_for_request(reader: &mut BufReader<TimedReader>, timeouts: &Timeouts) -> bool {
+    reader.get_mut().set_timeout(timeouts.keep_alive, None);
+    matches!(reader.fill_buf(), Ok(buf) if !buf.is_empty())
+}
+
+// Reads a request that started arriving. The head has to arrive within the request head deadline
+// and neither head nor body may stall for longer than their read timeouts, a request that takes
+// longer fails with ParseError::Incomplete.
+pub(crate) fn read_timed_request(
+    reader: &mut BufReader<TimedReader>,
+    timeouts: &Timeouts,
+    limits: &RequestLimits,
+) -> Result<HttpRequest, ParseError> {
+    reader.get_mut().set_timeout(timeouts.header_read, Some(Instant::now() + timeouts.request_head));
+    let head = read_request_head(reader, limits)?;
+    reader.get_mut().set_timeout(timeouts.body_read, None);
+    read_request_body(reader, head, limits)
+}
+
+#[test]
+fn times_out_trickled_request_head() {
+    use std::{io::Write, thread};
+
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    let timeouts = Timeouts {
+        keep_alive: Duration::from_secs(1),
+        header_read: Duration::from_millis(200),
+        request_head: Duration::from_millis(300),
+        body_read: Duration::from_secs(1),
+        write: Duration::from_secs(1),
+    };
+    // every byte arrives well within the read timeout, the head as a whole does not
+    let trickle = thread::spawn(move || {
+        for byte in b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n" {
+            if client.write_all(&[*byte]).is_err() {
+                break
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    });
+
+    let mut reader = BufReader::new(TimedReader::new(&stream, timeouts.keep_alive));
+    assert!(wait_for_request(&mut reader, &timeouts));
+    let started = Instant::now();
+    let request = read_timed_request(&mut reader, &timeouts, &RequestLimits::default());
+    assert!(matches!(request, Err(ParseError::Incomplete)));
+    assert!(started.elapsed() < Duration::from_millis(600));
+    trickle.join().unwrap();
+}
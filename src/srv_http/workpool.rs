@@ -1,80 +1,400 @@
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    panic::{self, AssertUnwindSafe},
+    sync::{atomic::{AtomicUsize, Ordering}, Arc, Condvar, Mutex},
     thread,
+    time::{Duration, Instant},
 };
+#[cfg(test)]
+use std::sync::mpsc;
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
 
 pub struct WorkerPool {
-    workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    sender: Option<Sender<Job>>,
+    shared: Arc<PoolShared>,
 }
 
-struct Worker {
-    id: usize,
-    thread: Option<thread::JoinHandle<()>>,
+// Counters for monitoring the pool, readable while the server runs through a ServerHandle
+pub struct PoolStats {
+    queue: Receiver<Job>,
+    panics: AtomicUsize,
+    workers: AtomicUsize,
+}
+
+impl PoolStats {
+    pub fn worker_count(&self) -> usize {
+        self.workers.load(Ordering::Relaxed)
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn panic_count(&self) -> usize {
+        self.panics.load(Ordering::Relaxed)
+    }
+
+    pub fn record_panic(&self) {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+    }
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
-impl Worker {
-    pub fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Self {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv();
-            match message {
-                Ok(job) => {
-                    println!("Worker {id} got a job; executing.");
-                    job();
-                }
-                Err(_) => {
-                    println!("Worker {id} disconnected; shutting down.");
-                    break;
+// State shared by the pool and its workers. The number of running workers is counted so that
+// shutting down can wait for them with a timeout, which joining a thread cannot do.
+//
+// Jobs are dispatched through a lock-free MPMC channel, every worker waits on the channel itself
+// instead of queueing up on a mutex around a single receiver first.
+struct PoolShared {
+    receiver: Receiver<Job>,
+    running: Mutex<usize>,
+    exited: Condvar,
+    idle: AtomicUsize,
+    next_id: AtomicUsize,
+    stats: Arc<PoolStats>,
+    min_workers: usize,
+    max_workers: usize,
+    keep_alive: Option<Duration>,
+}
+
+impl PoolShared {
+    fn set_running(&self, running: &mut usize, count: usize) {
+        *running = count;
+        self.stats.workers.store(count, Ordering::Relaxed);
+    }
+}
+
+// Lives on the stack of a worker thread. When the thread dies of a panic that escaped the job,
+// the sentinel spawns a replacement so the pool does not shrink.
+struct Sentinel {
+    id: usize,
+    shared: Arc<PoolShared>,
+    retired: bool,
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            println!("Worker {} died; spawning a replacement.", self.id);
+            self.shared.stats.record_panic();
+            spawn_worker(Arc::clone(&self.shared));
+        }
+        // a retired worker took itself off the count already
+        if !self.retired {
+            let mut running = self.shared.running.lock().unwrap();
+            let count = *running - 1;
+            self.shared.set_running(&mut running, count);
+        }
+        self.shared.exited.notify_all();
+    }
+}
+
+// An idle worker above the minimum retires once it waited keep_alive without getting a job. The
+// check and the decrement happen under one lock so the pool never drops below the minimum. A
+// worker does not retire while jobs are queued, they may have been sent after it timed out.
+fn try_retire(shared: &PoolShared) -> bool {
+    let mut running = shared.running.lock().unwrap();
+    if *running > shared.min_workers && shared.receiver.is_empty() {
+        let count = *running - 1;
+        shared.set_running(&mut running, count);
+        true
+    } else {
+        false
+    }
+}
+
+fn spawn_worker(shared: Arc<PoolShared>) {
+    {
+        let mut running = shared.running.lock().unwrap();
+        let count = *running + 1;
+        shared.set_running(&mut running, count);
+    }
+    start_worker(shared);
+}
+
+// starts the thread of a worker that was counted as running already
+fn start_worker(shared: Arc<PoolShared>) {
+    let id = shared.next_id.fetch_add(1, Ordering::Relaxed);
+    let sentinel = Sentinel {
+        id,
+        shared,
+        retired: false,
+    };
+    let spawned = thread::Builder::new()
+        .name(format!("worker {}", id))
+        .spawn(move || {
+            let shared = Arc::clone(&sentinel.shared);
+            let mut sentinel = sentinel;
+            loop {
+                shared.idle.fetch_add(1, Ordering::SeqCst);
+                let message = match shared.keep_alive {
+                    Some(keep_alive) => shared.receiver.recv_timeout(keep_alive),
+                    None => shared.receiver.recv().map_err(RecvTimeoutError::from),
+                };
+                shared.idle.fetch_sub(1, Ordering::SeqCst);
+                match message {
+                    Ok(job) => {
+                        if cfg!(debug_assertions) {
+                            println!("Worker {id} got a job; executing.");
+                        }
+                        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                            println!("Worker {id} job panicked.");
+                            shared.stats.record_panic();
+                            drop(payload);
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        if try_retire(&shared) {
+                            println!("Worker {id} idle; shutting down.");
+                            sentinel.retired = true;
+                            break;
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {
+                        println!("Worker {id} disconnected; shutting down.");
+                        break;
+                    }
                 }
             }
         });
-        Worker {
-            id,
-            thread: Some(thread),
-        }
+    if let Err(err) = spawned {
+        println!("Unable to spawn worker {}: {}", id, err);
     }
 }
 
 impl WorkerPool {
-    pub fn new(size: usize) -> Self {
-        assert!(size > 0, "Worker pool must be instantiated with more than 0 workers");
-        let mut workers = Vec::with_capacity(size);
+    pub fn new(size: usize, queue_capacity: usize) -> Self {
+        Self::build(size, size, None, queue_capacity)
+    }
+
+    // Starts with min_workers threads and grows up to max_workers while jobs are waiting for a
+    // worker. Workers above the minimum exit after being idle for keep_alive.
+    pub fn elastic(min_workers: usize, max_workers: usize, keep_alive: Duration, queue_capacity: usize) -> Self {
+        Self::build(min_workers, max_workers, Some(keep_alive), queue_capacity)
+    }
+
+    fn build(min_workers: usize, max_workers: usize, keep_alive: Option<Duration>, queue_capacity: usize) -> Self {
+        assert!(max_workers > 0, "Worker pool must be instantiated with more than 0 workers");
+        assert!(min_workers <= max_workers, "Worker pool minimum must not exceed its maximum");
+        assert!(queue_capacity > 0, "Worker pool queue must hold more than 0 jobs");
 
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
+        let (sender, receiver) = crossbeam_channel::bounded(queue_capacity);
+        let shared = Arc::new(PoolShared {
+            stats: Arc::new(PoolStats {
+                queue: receiver.clone(),
+                panics: AtomicUsize::new(0),
+                workers: AtomicUsize::new(0),
+            }),
+            receiver,
+            running: Mutex::new(0),
+            exited: Condvar::new(),
+            idle: AtomicUsize::new(0),
+            next_id: AtomicUsize::new(0),
+            min_workers,
+            max_workers,
+            keep_alive,
+        });
 
-        for id in 0..size {
-            let worker = Worker::new(id, Arc::clone(&receiver));
-            workers.push(worker);
+        for _ in 0..min_workers {
+            spawn_worker(Arc::clone(&shared));
         }
 
         WorkerPool {
-            workers,
             sender: Some(sender),
+            shared,
         }
     }
 
+    // blocks while the queue is full
     pub fn execute<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
         let job = Box::new(f);
-        self.sender.as_ref().unwrap().send(job).unwrap();
+        let sender = self.sender.as_ref().unwrap();
+        // this job and the ones already queued need more workers than are waiting
+        if sender.len() + 1 > self.shared.idle.load(Ordering::SeqCst) {
+            self.grow_if(|_| true);
+        }
+        sender.send(job).unwrap();
+        // the last worker of a pool without a minimum may have retired since the check above
+        if self.shared.min_workers == 0 {
+            self.grow_if(|running| running == 0);
+        }
+    }
+
+    // The check and the increment happen under one lock, acceptors calling execute at the same
+    // time cannot grow the pool past its maximum
+    fn grow_if<P: Fn(usize) -> bool>(&self, needed: P) {
+        let mut running = self.shared.running.lock().unwrap();
+        if *running < self.shared.max_workers && needed(*running) {
+            let count = *running + 1;
+            self.shared.set_running(&mut running, count);
+            drop(running);
+            start_worker(Arc::clone(&self.shared));
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.sender.as_ref().is_some_and(|s| s.is_full())
+    }
+
+    pub fn stats(&self) -> Arc<PoolStats> {
+        Arc::clone(&self.shared.stats)
+    }
+
+    // Lets the workers finish the queued jobs and waits for them to exit. Returns false when the
+    // timeout passed first, the remaining workers are then left to finish on their own.
+    pub fn shutdown(mut self, timeout: Option<Duration>) -> bool {
+        drop(self.sender.take());
+        self.wait_for_workers(timeout)
+    }
+
+    fn wait_for_workers(&self, timeout: Option<Duration>) -> bool {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut running = self.shared.running.lock().unwrap();
+        while *running > 0 {
+            running = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    self.shared.exited.wait_timeout(running, deadline - now).unwrap().0
+                }
+                None => self.shared.exited.wait(running).unwrap(),
+            };
+        }
+        *running == 0
     }
 }
 
 impl Drop for WorkerPool {
     fn drop(&mut self) {
-        drop(self.sender.take());
-        for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
-
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
-            }
+        // a pool that was shut down already waited for its workers
+        if let Some(sender) = self.sender.take() {
+            drop(sender);
+            println!("Shutting down workers");
+            self.wait_for_workers(None);
         }
     }
 }
 
+#[test]
+fn shutdown_drains_queued_jobs() {
+    let pool = WorkerPool::new(2, 16);
+    let done = Arc::new(Mutex::new(0));
+    for _ in 0..8 {
+        let done = Arc::clone(&done);
+        pool.execute(move || *done.lock().unwrap() += 1);
+    }
+    assert!(pool.shutdown(None));
+    assert_eq!(*done.lock().unwrap(), 8);
+}
+
+#[test]
+fn shutdown_gives_up_after_timeout() {
+    let pool = WorkerPool::new(1, 16);
+    pool.execute(|| thread::sleep(Duration::from_secs(2)));
+    assert!(!pool.shutdown(Some(Duration::from_millis(50))));
+}
+
+#[test]
+fn reports_full_queue() {
+    let pool = WorkerPool::new(1, 2);
+    let (started_sender, started) = mpsc::channel();
+    let (release, released) = mpsc::channel::<()>();
+    pool.execute(move || {
+        started_sender.send(()).unwrap();
+        released.recv().unwrap();
+    });
+    started.recv().unwrap();
+    pool.execute(|| ());
+    assert!(!pool.is_full());
+    pool.execute(|| ());
+    assert!(pool.is_full());
+    assert_eq!(pool.stats().queue_depth(), 2);
+    release.send(()).unwrap();
+    assert!(pool.shutdown(None));
+}
+
+#[allow(dead_code)]
+struct PanicOnDrop;
+
+impl Drop for PanicOnDrop {
+    fn drop(&mut self) {
+        panic!("payload dropped");
+    }
+}
+
+#[test]
+fn survives_panicking_jobs() {
+    let pool = WorkerPool::new(1, 16);
+    pool.execute(|| panic!("job failed"));
+    // the panic payload panics again outside of the job and takes the worker thread down
+    pool.execute(|| panic::panic_any(PanicOnDrop));
+    let (done_sender, done) = mpsc::channel();
+    pool.execute(move || done_sender.send(()).unwrap());
+    done.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(pool.stats().panic_count(), 3);
+    assert!(pool.shutdown(None));
+}
+
+#[test]
+fn grows_under_load_and_shrinks_when_idle() {
+    let pool = WorkerPool::elastic(1, 4, Duration::from_millis(100), 16);
+    assert_eq!(pool.stats().worker_count(), 1);
+    let (release, released) = mpsc::channel::<()>();
+    let released = Arc::new(Mutex::new(released));
+    for _ in 0..4 {
+        let released = Arc::clone(&released);
+        pool.execute(move || {
+            let _ = released.lock().unwrap().recv();
+        });
+    }
+    assert_eq!(pool.stats().worker_count(), 4);
+    drop(release);
+    thread::sleep(Duration::from_millis(800));
+    assert_eq!(pool.stats().worker_count(), 1);
+    assert!(pool.shutdown(None));
+}
+
+#[test]
+fn concurrent_submitters_stay_within_max_workers() {
+    let pool = Arc::new(WorkerPool::elastic(0, 3, Duration::from_secs(5), 64));
+    let (release, released) = mpsc::channel::<()>();
+    let released = Arc::new(Mutex::new(released));
+    let submitters: Vec<thread::JoinHandle<()>> = (0..8)
+        .map(|_| {
+            let pool = Arc::clone(&pool);
+            let released = Arc::clone(&released);
+            thread::spawn(move || {
+                for _ in 0..4 {
+                    let released = Arc::clone(&released);
+                    pool.execute(move || {
+                        let _ = released.lock().unwrap().recv();
+                    });
+                }
+            })
+        })
+        .collect();
+    for submitter in submitters {
+        submitter.join().unwrap();
+    }
+    assert!(pool.stats().worker_count() <= 3);
+    drop(release);
+    assert!(Arc::into_inner(pool).unwrap().shutdown(None));
+}
+
+#[test]
+fn pool_without_minimum_runs_jobs_after_shrinking() {
+    let pool = WorkerPool::elastic(0, 1, Duration::from_millis(1), 16);
+    for _ in 0..50 {
+        let (done_sender, done) = mpsc::channel();
+        pool.execute(move || done_sender.send(()).unwrap());
+        done.recv_timeout(Duration::from_secs(5)).unwrap();
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert!(pool.shutdown(None));
+}